@@ -0,0 +1,251 @@
+use image::{ImageBuffer, Rgb, RgbImage};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use super::vector::*;
+use super::colour::*;
+use super::material::*;
+
+//////////////////////////////////////////////////////////////////////
+//// Extra pictures that can be saved alongside the finished one, each
+//// showing one thing about what the camera rays hit first
+//////////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Aov {
+    Depth, // How far away, from black (nearest) to white (far away, or nothing at all)
+    Normal, // Shading normals in world space, as colours like a normal map
+    Albedo, // The surface's colour, unlit
+    ObjectId, // A different flat colour for each object
+    MaterialId, // And for each material
+    Direct, // Light straight from the lights onto the first surface
+    Indirect, // Light that bounced off something else first
+    Emission, // Light given off by what was hit (or the sky, if nothing was)
+}
+
+pub const ALL_AOVS: [Aov; 8] = [Aov::Depth, Aov::Normal, Aov::Albedo, Aov::ObjectId, Aov::MaterialId,
+    Aov::Direct, Aov::Indirect, Aov::Emission];
+
+impl Aov {
+    pub fn parse(name: &str) -> Result<Aov, String> {
+        ALL_AOVS.iter().copied().find(|aov| aov.name() == name).ok_or(format!("Unknown AOV {}", name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object",
+            Aov::MaterialId => "material",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+        }
+    }
+
+    pub fn filename(&self, beauty: &str) -> String {
+        //// Named after the finished picture; "result.png" has its depth in "result_depth.png"
+        match beauty.rsplit_once('.') {
+            Some((stem, extension)) => format!("{}_{}.{}", stem, self.name(), extension),
+            None => format!("{}_{}", beauty, self.name()),
+        }
+    }
+}
+
+pub fn material_id(material: &Material) -> u64 {
+    //// Something to tell materials apart by. Materials are copied into
+    //// each object, so this goes by what they're like rather than where
+    //// they are; identical materials count as the same one
+    let mut hasher = DefaultHasher::new();
+    (material.brdf as usize).hash(&mut hasher);
+    for value in [material.albedo, material.prob, material.diffuse] {
+        value.to_bits().hash(&mut hasher);
+    }
+    material.cos.hash(&mut hasher);
+    material.emission.as_ref().map(|emission| (emission.strength.to_bits(), emission.two_sided)).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn id_colour(id: u64) -> Colour {
+    //// A flat colour for an ID, bright enough to tell apart from nothing (black)
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    let bits = hasher.finish();
+    let channel = |shift: u32| 0.2 + 0.8 * ((bits >> shift) & 0xff) as f32 / 255.;
+    Colour::new(channel(0), channel(8), channel(16))
+}
+
+///////////////////////////////////////////////////////////////////
+//// What one camera ray found; the lighting parts add up to what
+//// the ray brought back, so direct + indirect + emission is the
+//// finished picture (other than for rays that hit a volume first)
+///////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct AovSample {
+    pub depth: Option<f32>, // Distance to the first thing hit
+    pub normal: Vec3,
+    pub albedo: Colour,
+    pub object: Option<usize>,
+    pub material: Option<u64>,
+    pub direct: Colour,
+    pub indirect: Colour,
+    pub emission: Colour,
+}
+
+impl Default for AovSample {
+    fn default() -> AovSample {
+        //// A ray that didn't find anything (or couldn't be made)
+        AovSample {
+            depth: None,
+            normal: Vec3::new(0., 0., 0.),
+            albedo: Colour::black(),
+            object: None,
+            material: None,
+            direct: Colour::black(),
+            indirect: Colour::black(),
+            emission: Colour::black(),
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+//// Every sample in a pixel, averaged (with no filter, since normals or
+//// IDs blurred into their neighbours don't mean anything). IDs can't be
+//// averaged at all, so they're whatever the first sample hit
+//////////////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct AovPixel {
+    count: u32,
+    hits: u32, // Samples which hit something, which depth is averaged over
+    depth: f32,
+    normal: Vec3,
+    albedo: Colour,
+    object: Option<usize>,
+    material: Option<u64>,
+    direct: Colour,
+    indirect: Colour,
+    emission: Colour,
+}
+
+impl Default for AovPixel {
+    fn default() -> AovPixel {
+        AovPixel {
+            count: 0,
+            hits: 0,
+            depth: 0.,
+            normal: Vec3::new(0., 0., 0.),
+            albedo: Colour::black(),
+            object: None,
+            material: None,
+            direct: Colour::black(),
+            indirect: Colour::black(),
+            emission: Colour::black(),
+        }
+    }
+}
+
+impl AovPixel {
+    pub fn add(&mut self, sample: &AovSample) {
+        if self.count == 0 {
+            self.object = sample.object;
+            self.material = sample.material;
+        }
+        self.count += 1;
+        if let Some(depth) = sample.depth {
+            self.hits += 1;
+            self.depth += depth;
+        }
+        self.normal += sample.normal;
+        self.albedo += sample.albedo;
+        self.direct += sample.direct;
+        self.indirect += sample.indirect;
+        self.emission += sample.emission;
+    }
+
+    fn mean(&self, total: Colour) -> Colour {
+        total / self.count.max(1) as f32
+    }
+
+    pub fn depth(&self) -> Option<f32> {
+        (self.hits > 0).then(|| self.depth / self.hits as f32)
+    }
+
+    pub fn normal(&self) -> Vec3 {
+        //// The average normal, which is zero where nothing was hit
+        if self.normal.length_squared() > 0. {self.normal.normalise()} else {self.normal}
+    }
+
+    pub fn albedo(&self) -> Colour {
+        self.mean(self.albedo)
+    }
+
+    pub fn direct(&self) -> Colour {
+        self.mean(self.direct)
+    }
+
+    pub fn indirect(&self) -> Colour {
+        self.mean(self.indirect)
+    }
+
+    pub fn emission(&self) -> Colour {
+        self.mean(self.emission)
+    }
+}
+
+//////////////////////////////////////
+//// Every pixel's AOVs, for a picture
+//////////////////////////////////////
+pub struct Aovs {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<AovPixel>,
+}
+
+impl Aovs {
+    pub fn new(width: u32, height: u32) -> Aovs {
+        Aovs {width, height, pixels: vec![AovPixel::default(); (width * height) as usize]}
+    }
+
+    pub fn set_row(&mut self, y: u32, row: Vec<AovPixel>) {
+        let start = (y * self.width) as usize;
+        self.pixels[start..start + row.len()].copy_from_slice(&row);
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &AovPixel {
+        &self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn image(&self, aov: Aov) -> RgbImage {
+        // Depths are scaled so nearly everything hit is darker than white; going by
+        // the very furthest, a floor stretching off to the horizon makes it all black
+        let mut depths: Vec<f32> = self.pixels.iter().filter_map(|pixel| pixel.depth()).collect();
+        depths.sort_by(f32::total_cmp);
+        let furthest = depths.get(depths.len() * 95 / 100).copied().unwrap_or(1.);
+
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let pixel = self.pixel(x, y);
+            match aov {
+                Aov::Depth => {
+                    let t = pixel.depth().map_or(1., |depth| clamp(depth / furthest.max(1e-6)));
+                    Rgb([(t * 255.) as u8; 3])
+                },
+                Aov::Normal => {
+                    // Stored as is rather than gamma encoded, like normal maps are
+                    let n = (pixel.normal() + Vec3::new(1., 1., 1.)) * 0.5;
+                    Rgb([(n.x() * 255.) as u8, (n.y() * 255.) as u8, (n.z() * 255.) as u8])
+                },
+                Aov::Albedo => pixel.albedo().to_srgb(),
+                Aov::ObjectId => pixel.object.map_or(Colour::black(), |id| id_colour(id as u64)).to_srgb(),
+                Aov::MaterialId => pixel.material.map_or(Colour::black(), id_colour).to_srgb(),
+                Aov::Direct => pixel.direct().to_rgb(),
+                Aov::Indirect => pixel.indirect().to_rgb(),
+                Aov::Emission => pixel.emission().to_rgb(),
+            }
+        })
+    }
+
+    pub fn save(&self, aovs: &[Aov], beauty: &str) {
+        for aov in aovs {
+            self.image(*aov).save(aov.filename(beauty)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,722 @@
+use super::vector::*;
+use super::colour::*;
+use super::primitive::*;
+use super::ray::*;
+use super::scene::*;
+use super::sampler::*;
+use super::rng::*;
+use super::aov::*;
+use super::material::*;
+use super::integrator::*;
+
+///////////////////////////////////////////////////////////////////////////
+//// Bidirectional path tracing (Veach's, laid out like pbrt's). A path is
+//// traced out from the camera and another from a glowing object, and
+//// every vertex of one is joined to every vertex of the other. Each way
+//// of making a path is weighted by how likely it was to be made that way
+//// compared to all the others (the balance heuristic), so whichever is
+//// best at finding some light counts most for it; like light going
+//// through glass onto the floor, which is only found from the light's
+//// end. Paths joined straight to the camera land on whichever pixel
+//// they land on, so they're splatted onto the film.
+////
+//// Only diffuse surfaces can be joined at; mirrors, glass and metal can
+//// only be bounced off. The point, spot and directional lights, and the
+//// background, can't be traced from, so they're only found from the
+//// camera's end. Volumes are left out altogether
+///////////////////////////////////////////////////////////////////////////
+pub struct Bdpt {
+    pub max_depth: u32, // Most bounces a path can have
+    emitters: Vec<usize>, // Objects which light paths start from
+}
+
+impl Default for Bdpt {
+    fn default() -> Bdpt {
+        Bdpt {max_depth: 5, emitters: vec![]}
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Camera,
+    Light, // The start of a light path, on a glowing object
+    Surface,
+}
+
+// Which way along the path light is being followed; from the camera it's
+// radiance, and from the lights it's importance
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Radiance,
+    Importance,
+}
+
+#[derive(Copy, Clone)]
+struct Vertex<'a> {
+    kind: Kind,
+    point: Vec3,
+    normal: Vec3, // Geometric and outwards; zero for the camera
+    shading: Vec3,
+    wo: Vec3, // Back towards the vertex before this one
+    beta: Colour, // What the path up to here carries, over the pdf of making it
+    pdf_fwd: f32, // Of this vertex, per area, made from the one before
+    pdf_rev: f32, // And from the one after, if the path had gone the other way
+    delta: bool, // Bounced off something that can't be joined at
+    material: Option<&'a Material>,
+    colour: Colour,
+    uv: (f32, f32),
+    id: Option<usize>, // Which object it's on
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(point: Vec3, beta: Colour) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Camera,
+            point,
+            normal: Vec3::new(0., 0., 0.),
+            shading: Vec3::new(0., 0., 0.),
+            wo: Vec3::new(0., 0., 0.),
+            beta,
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+            delta: false,
+            material: None,
+            colour: Colour::white(),
+            uv: (0., 0.),
+            id: None,
+        }
+    }
+
+    fn light(sample: &SurfaceSample<'a>, id: usize, beta: Colour, pdf: f32) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Light,
+            point: sample.point,
+            normal: sample.normal,
+            shading: sample.normal,
+            beta,
+            pdf_fwd: pdf,
+            material: Some(sample.material),
+            uv: sample.uv,
+            id: Some(id),
+            ..Vertex::camera(sample.point, beta)
+        }
+    }
+
+    fn surface(hit: &SurfaceInteraction<'a>, wo: Vec3, beta: Colour) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Surface,
+            point: hit.point,
+            normal: hit.normal,
+            shading: hit.shading,
+            wo,
+            beta,
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+            delta: false,
+            material: Some(hit.material),
+            colour: hit.colour,
+            uv: hit.uv,
+            id: Some(hit.id),
+        }
+    }
+
+    fn connectible(&self) -> bool {
+        match self.kind {
+            Kind::Camera | Kind::Light => true,
+            Kind::Surface => self.material.is_some_and(|material| material.diffuse > 0.),
+        }
+    }
+
+    fn emissive(&self) -> bool {
+        self.material.is_some_and(|material| material.emission.is_some())
+    }
+
+    fn le(&self, towards: Vec3) -> Colour {
+        //// Light given off from here towards a point
+        match self.material.and_then(|material| material.emission.as_ref()) {
+            Some(emission) => emission.radiance(self.uv, self.point, dot(towards - self.point, self.normal) > 0.),
+            None => Colour::black(),
+        }
+    }
+
+    fn f(&self, wi: Vec3, mode: Mode) -> Colour {
+        //// The BSDF between wo and wi (which are both away from the surface)
+        if self.kind != Kind::Surface || !self.connectible() {
+            return Colour::black();
+        }
+        // Diffuse surfaces only reflect
+        if dot(wi, self.normal) * dot(self.wo, self.normal) <= 0. {
+            return Colour::black();
+        }
+        let material = self.material.unwrap();
+        self.colour * (material.albedo * material.diffuse / PI) * correct_shading(self, wi, mode)
+    }
+
+    fn f_to(&self, next: &Vertex, mode: Mode) -> Colour {
+        self.f((next.point - self.point).normalise(), mode)
+    }
+
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        //// A pdf per solid angle seen from here, as a pdf per area at the next vertex
+        let to = next.point - self.point;
+        let distance_squared = to.length_squared();
+        if distance_squared == 0. {
+            return 0.;
+        }
+        let cos = if next.kind == Kind::Camera {1.} else {dot(next.normal, to.normalise()).abs()};
+        pdf * cos / distance_squared
+    }
+
+    fn pdf(&self, context: &Context, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        //// How likely this vertex was to have made the next one, coming from prev
+        if self.kind == Kind::Light {
+            return self.pdf_light(next);
+        }
+        let wn = (next.point - self.point).normalise();
+
+        let pdf = match self.kind {
+            Kind::Camera => {
+                let ray = Ray::new(self.point, wn, 0.);
+                context.camera.pdf_direction(ray, context.aspect()).unwrap_or(0.)
+            },
+            _ => {
+                let Some(prev) = prev else {return 0.};
+                if !self.connectible() {
+                    return 0.;
+                }
+                let wp = (prev.point - self.point).normalise();
+                let n = if dot(self.shading, wp) > 0. {self.shading} else {-self.shading};
+                cosine_pdf(n, wn)
+            },
+        };
+
+        self.convert_density(pdf, next)
+    }
+
+    fn pdf_light(&self, next: &Vertex) -> f32 {
+        //// How likely a light path from here was to go towards the next vertex
+        let Some(emission) = self.material.and_then(|material| material.emission.as_ref()) else {return 0.};
+        let w = (next.point - self.point).normalise();
+        let cos = dot(self.normal, w);
+
+        let pdf = if emission.two_sided {cos.abs() / (2. * PI)} else {cos.max(0.) / PI};
+        self.convert_density(pdf, next)
+    }
+}
+
+// Both halves of a path, before they're joined
+struct Paths<'a> {
+    light: Vec<Vertex<'a>>,
+    camera: Vec<Vertex<'a>>,
+    time: f32,
+    joinable: bool, // Whether light paths can be joined straight to the camera
+}
+
+fn correct_shading(vertex: &Vertex, wi: Vec3, mode: Mode) -> f32 {
+    //// Shading normals make the BSDF asymmetric; going from the lights, this
+    //// puts back what they changed (Veach's adjoint BSDF, as in pbrt)
+    if mode == Mode::Radiance {
+        return 1.;
+    }
+    let numerator = dot(vertex.wo, vertex.shading).abs() * dot(wi, vertex.normal).abs();
+    let denominator = dot(vertex.wo, vertex.normal).abs() * dot(wi, vertex.shading).abs();
+    if denominator == 0. {0.} else {numerator / denominator}
+}
+
+fn cosine_pdf(n: Vec3, w: Vec3) -> f32 {
+    dot(n, w).max(0.) / PI
+}
+
+fn spawn(point: Vec3, normal: Vec3, d: Vec3, time: f32) -> Ray {
+    //// A ray leaving a surface, nudged off it onto whichever side it's going
+    Ray::new(point + normal * if dot(d, normal) > 0. {0.01} else {-0.01}, d, time)
+}
+
+fn visible(scene: &Scene, a: &Vertex, b: &Vertex, time: f32) -> bool {
+    //// Whether nothing's in the way between two vertices
+    let to = b.point - a.point;
+    let distance = to.length();
+    let d = to / distance;
+    let ray = spawn(a.point, a.normal, d, time);
+    // Nudged off the far end too
+    let end = b.point + b.normal * if dot(-d, b.normal) > 0. {0.01} else {-0.01};
+    let length = dot(end - ray.o, d);
+    !scene.intersect(ray).is_some_and(|hit| hit.t < length)
+}
+
+fn g(scene: &Scene, a: &Vertex, b: &Vertex, time: f32) -> f32 {
+    //// How well two vertices see each other
+    let to = b.point - a.point;
+    let distance_squared = to.length_squared();
+    let d = to.normalise();
+    let cos_a = if a.kind == Kind::Camera {1.} else {dot(a.shading, d).abs()};
+    let cos_b = if b.kind == Kind::Camera {1.} else {dot(b.shading, d).abs()};
+    if !visible(scene, a, b, time) {
+        return 0.;
+    }
+    cos_a * cos_b / distance_squared
+}
+
+fn remap(pdf: f32) -> f32 {
+    //// Delta vertices have zero pdfs, which cancel out in the ratios anyway
+    if pdf != 0. {pdf} else {1.}
+}
+
+fn is_black(col: Colour) -> bool {
+    col.r() <= 0. && col.g() <= 0. && col.b() <= 0.
+}
+
+impl Bdpt {
+    fn emitter_pdf(&self, id: Option<usize>, scene: &Scene) -> f32 {
+        //// How likely a light path was to start at a point on some object (per area)
+        match id {
+            Some(id) if self.emitters.contains(&id) => {
+                let area = scene.objects[id].sample_surface((0.5, 0.5)).map_or(0., |sample| sample.area);
+                if area > 0. {1. / (self.emitters.len() as f32 * area)} else {0.}
+            },
+            _ => 0.,
+        }
+    }
+
+    fn sample_emitter<'a>(&self, scene: &'a Scene, sampler: &mut dyn Sampler) -> Option<(SurfaceSample<'a>, usize, f32)> {
+        //// A point on a glowing object, picked evenly from the objects then their areas,
+        //// and its pdf (per area)
+        let choice = sampler.get_1d();
+        let u = sampler.get_2d();
+        if self.emitters.is_empty() {
+            return None;
+        }
+        let id = self.emitters[((choice * self.emitters.len() as f32) as usize).min(self.emitters.len() - 1)];
+        let sample = scene.objects[id].sample_surface(u)?;
+        let pdf = 1. / (self.emitters.len() as f32 * sample.area);
+        Some((sample, id, pdf))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<'a>(&self, context: &Context<'a>, mut ray: Ray, mut beta: Colour, pdf: f32, most: usize, mode: Mode,
+            sampler: &mut dyn Sampler, path: &mut Vec<Vertex<'a>>) -> Colour {
+        //// Carry on a path from its last vertex, adding up to `most` more. Gives
+        //// whatever the background adds if the path leaves the scene from the camera
+        let start = path.len();
+        let mut pdf_fwd = pdf;
+
+        while path.len() - start < most {
+            let Some(hit) = context.scene.intersect(ray) else {
+                if mode == Mode::Radiance {
+                    return beta * context.scene.background.radiance(ray.d.normalise());
+                }
+                break;
+            };
+
+            let d = ray.d.normalise();
+            let mut vertex = Vertex::surface(&hit, -d, beta);
+            vertex.pdf_fwd = path.last().unwrap().convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            if path.len() - start >= most {
+                break;
+            }
+
+            // Pick which way to go next
+            let material = hit.material;
+            let pdf_rev;
+            let wi;
+            if vertex.connectible() {
+                let n = if dot(vertex.shading, vertex.wo) > 0. {vertex.shading} else {-vertex.shading};
+                wi = cosine_sample(n, sampler.get_2d());
+                pdf_fwd = cosine_pdf(n, wi);
+                pdf_rev = cosine_pdf(n, vertex.wo);
+
+                let f = vertex.f(wi, mode);
+                if is_black(f) || pdf_fwd <= 0. {
+                    break;
+                }
+                beta = beta * f * (dot(wi, vertex.shading).abs() / pdf_fwd);
+            } else {
+                // The same as the path tracer does
+                wi = (material.brdf)(hit.shading, d, sampler).normalise();
+                let albedo = material.albedo * if material.cos {clamp(dot(d, -hit.shading))} else {1.};
+                beta = beta * hit.colour * (albedo / (PI * material.prob));
+                pdf_fwd = 0.;
+                pdf_rev = 0.;
+                path.last_mut().unwrap().delta = true;
+            }
+
+            ray = spawn(hit.point, hit.normal, wi, ray.time);
+            let n = path.len();
+            path[n - 2].pdf_rev = path[n - 1].convert_density(pdf_rev, &path[n - 2]);
+        }
+
+        Colour::black()
+    }
+
+    fn light_path<'a>(&self, context: &Context<'a>, time: f32, sampler: &mut dyn Sampler) -> Vec<Vertex<'a>> {
+        //// A path starting somewhere on a glowing object
+        let mut path = vec![];
+        let Some((sample, id, pdf_position)) = self.sample_emitter(context.scene, sampler) else {return path};
+        let emission = sample.material.emission.as_ref().unwrap();
+
+        // Which side to leave from, then which way
+        let side = sampler.get_1d();
+        let u = sampler.get_2d();
+        let (normal, side_pdf) = if !emission.two_sided {
+            (sample.normal, 1.)
+        } else if side < 0.5 {
+            (sample.normal, 0.5)
+        } else {
+            (-sample.normal, 0.5)
+        };
+        let d = cosine_sample(normal, u);
+        let pdf_direction = cosine_pdf(normal, d) * side_pdf;
+        let le = emission.radiance(sample.uv, sample.point, dot(d, sample.normal) > 0.);
+        if pdf_direction <= 0. || is_black(le) {
+            return path;
+        }
+
+        path.push(Vertex::light(&sample, id, le / pdf_position, pdf_position));
+        let beta = le * (dot(normal, d) / (pdf_position * pdf_direction));
+        let ray = spawn(sample.point, sample.normal, d, time);
+        self.random_walk(context, ray, beta, pdf_direction, self.max_depth as usize, Mode::Importance, sampler, &mut path);
+        path
+    }
+
+    fn connect(&self, context: &Context, paths: &Paths, s: usize, t: usize, sampler: &mut dyn Sampler) -> (Colour, Option<(f32, f32)>) {
+        //// The light along the path made of the first s vertices of the light path and
+        //// the first t of the camera path, weighted; and where it lands on the film, if
+        //// it's joined straight to the camera
+        let (light, camera, time) = (&paths.light, &paths.camera, paths.time);
+        let scene = context.scene;
+        let mut sampled = None;
+        let mut film = None;
+        let mut l = Colour::black();
+
+        if s == 0 {
+            // The camera path found a light by itself
+            let pt = &camera[t - 1];
+            if pt.emissive() {
+                l = pt.le(camera[t - 2].point) * pt.beta;
+                if self.emitter_pdf(pt.id, scene) == 0. {
+                    // Nothing else could have found this light
+                    return (l, None);
+                }
+            }
+        } else if t == 1 {
+            // Join the light path to the camera
+            let qs = &light[s - 1];
+            if qs.connectible() {
+                if let Some(incoming) = context.camera.sample_incoming(qs.point, sampler.get_2d(), context.aspect()) {
+                    if incoming.pdf > 0. && incoming.importance > 0. {
+                        let vertex = Vertex::camera(incoming.lens, Colour::white() * (incoming.importance / incoming.pdf));
+                        let wi = (incoming.lens - qs.point).normalise();
+                        l = qs.beta * qs.f(wi, Mode::Importance) * vertex.beta;
+                        if qs.kind == Kind::Surface {
+                            l = l * dot(wi, qs.shading).abs();
+                        }
+                        if !is_black(l) && !visible(scene, qs, &vertex, time) {
+                            l = Colour::black();
+                        }
+                        film = Some(context.raster(incoming.film));
+                        sampled = Some(vertex);
+                    }
+                }
+            }
+        } else if s == 1 {
+            // Join the camera path to a new point on a light
+            let pt = &camera[t - 1];
+            if pt.connectible() {
+                if let Some((sample, id, pdf_position)) = self.sample_emitter(scene, sampler) {
+                    let to = sample.point - pt.point;
+                    let wi = to.normalise();
+                    let cos = dot(sample.normal, -wi).abs();
+                    if cos > 0. {
+                        let pdf = pdf_position * to.length_squared() / cos;
+                        let mut vertex = Vertex::light(&sample, id, Colour::black(), pdf_position);
+                        vertex.beta = vertex.le(pt.point) / pdf;
+
+                        l = pt.beta * pt.f(wi, Mode::Radiance) * vertex.beta * dot(wi, pt.shading).abs();
+                        if !is_black(l) && !visible(scene, pt, &vertex, time) {
+                            l = Colour::black();
+                        }
+                        sampled = Some(vertex);
+                    }
+                }
+            }
+        } else {
+            // Join the two paths in the middle
+            let (qs, pt) = (&light[s - 1], &camera[t - 1]);
+            if qs.connectible() && pt.connectible() {
+                l = qs.beta * qs.f_to(pt, Mode::Importance) * pt.f_to(qs, Mode::Radiance) * pt.beta;
+                if !is_black(l) {
+                    l = l * g(scene, qs, pt, time);
+                }
+            }
+        }
+
+        if is_black(l) {
+            return (Colour::black(), None);
+        }
+        (l * self.mis_weight(context, paths, sampled, s, t), film)
+    }
+
+    fn mis_weight(&self, context: &Context, paths: &Paths, sampled: Option<Vertex>, s: usize, t: usize) -> f32 {
+        //// The balance heuristic; how likely this way of making the path was,
+        //// over how likely all the ways of making it were put together
+        if s + t == 2 {
+            return 1.;
+        }
+        let (mut light, mut camera) = (paths.light[..s].to_vec(), paths.camera[..t].to_vec());
+        if s == 1 {
+            light[0] = sampled.unwrap();
+        } else if t == 1 {
+            camera[0] = sampled.unwrap();
+        }
+
+        // The ends that were joined can always be joined at
+        if t > 0 {
+            camera[t - 1].delta = false;
+        }
+        if s > 0 {
+            light[s - 1].delta = false;
+        }
+
+        // How likely each end was to have been made from the other side
+        if t > 0 {
+            camera[t - 1].pdf_rev = if s > 0 {
+                light[s - 1].pdf(context, if s > 1 {Some(&light[s - 2])} else {None}, &camera[t - 1])
+            } else {
+                self.emitter_pdf(camera[t - 1].id, context.scene)
+            };
+        }
+        if t > 1 {
+            camera[t - 2].pdf_rev = if s > 0 {
+                camera[t - 1].pdf(context, Some(&light[s - 1]), &camera[t - 2])
+            } else {
+                camera[t - 1].pdf_light(&camera[t - 2])
+            };
+        }
+        if s > 0 {
+            light[s - 1].pdf_rev = camera[t - 1].pdf(context, if t > 1 {Some(&camera[t - 2])} else {None}, &light[s - 1]);
+        }
+        if s > 1 {
+            light[s - 2].pdf_rev = light[s - 1].pdf(context, Some(&camera[t - 1]), &light[s - 2]);
+        }
+
+        let mut sum = 0.;
+
+        // Moving the join towards the camera, one vertex at a time
+        let mut ratio = 1.;
+        for i in (1..t).rev() {
+            ratio *= remap(camera[i].pdf_rev) / remap(camera[i].pdf_fwd);
+            // Cameras that can't be joined to can't make paths with only one camera vertex
+            if !camera[i].delta && !camera[i - 1].delta && (i > 1 || paths.joinable) {
+                sum += ratio;
+            }
+        }
+
+        // And towards the light
+        let mut ratio = 1.;
+        for i in (0..s).rev() {
+            ratio *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+            let delta_before = i > 0 && light[i - 1].delta;
+            if !light[i].delta && !delta_before {
+                sum += ratio;
+            }
+        }
+
+        1. / (1. + sum)
+    }
+}
+
+impl Integrator for Bdpt {
+    fn preprocess(&mut self, scene: &Scene, _: usize) {
+        self.emitters = scene.emitters();
+    }
+
+    fn li(&self, ray: Ray, context: &Context, sampler: &mut dyn Sampler, rng: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        let scene = context.scene;
+        let time = ray.time;
+        let max_depth = self.max_depth as usize;
+
+        // The camera path, then the light path
+        let pdf = context.camera.pdf_direction(ray, context.aspect());
+        let mut camera = vec![Vertex::camera(ray.o, Colour::white())];
+        let escaped = self.random_walk(context, ray, Colour::white(), pdf.unwrap_or(1.), max_depth + 1, Mode::Radiance, sampler, &mut camera);
+        let light = self.light_path(context, time, sampler);
+        let paths = Paths {light, camera, time, joinable: pdf.is_some()};
+        let camera = &paths.camera;
+
+        // Lighting by how many bounces it took, for the AOVs
+        let mut emission = if camera.len() == 1 {escaped} else {Colour::black()};
+        let mut direct = Colour::black();
+        let mut indirect = if camera.len() > 1 {escaped} else {Colour::black()};
+        let mut add = |col: Colour, depth: usize| match depth {
+            0 => emission += col,
+            1 => direct += col,
+            _ => indirect += col,
+        };
+
+        for t in 1..=camera.len() {
+            for s in 0..=paths.light.len() {
+                let depth = s as isize + t as isize - 2;
+                // A light seen straight from the camera is already found from the camera's end
+                let unjoinable = t == 1 && (s == 1 || !paths.joinable);
+                if unjoinable || depth < 0 || depth as usize > max_depth {
+                    continue;
+                }
+                let (col, film) = self.connect(context, &paths, s, t, sampler);
+                match film {
+                    Some(film) => context.splats.add(film, col),
+                    None => add(col, depth as usize),
+                }
+            }
+        }
+
+        // The lights that can't be traced from, onto each diffuse vertex of the camera path
+        for (i, vertex) in camera.iter().enumerate().skip(1) {
+            if i > max_depth || !vertex.connectible() {
+                continue;
+            }
+            for light in scene.lights.iter() {
+                if let Some(sample) = light.sample(vertex.point, sampler.get_2d()) {
+                    let f = vertex.f(sample.direction, Mode::Radiance);
+                    if is_black(f) {
+                        continue;
+                    }
+                    let from = vertex.point + vertex.normal * if dot(sample.direction, vertex.normal) > 0. {0.01} else {-0.01};
+                    let seen = shadow(scene, from, &sample, time, rng);
+                    add(vertex.beta * f * sample.radiance * (dot(sample.direction, vertex.shading).abs() * seen), i);
+                }
+            }
+        }
+
+        if let Some(aov) = aov {
+            if let Some(first) = camera.get(1) {
+                let material = first.material.unwrap();
+                aov.depth = Some((first.point - ray.o).length());
+                aov.normal = first.shading;
+                aov.albedo = first.colour * material.albedo;
+                aov.object = first.id;
+                aov.material = Some(material_id(material));
+            }
+            aov.direct = direct;
+            aov.indirect = indirect;
+            aov.emission = emission;
+        }
+
+        emission + direct + indirect
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::render::*;
+    use crate::film::*;
+
+    // Looks out every way from the middle of the scene; light can't be traced back into it
+    struct Eye;
+
+    impl Camera for Eye {
+        fn generate_ray(&self, (u, v): (f32, f32), _: (f32, f32), time: f32) -> Option<Ray> {
+            let z = 1. - 2. * u;
+            let across = (1. - z * z).max(0.).sqrt();
+            let phi = 2. * PI * v;
+            Some(Ray::new(Vec3::new(0., 0., 0.), Vec3::new(across * phi.cos(), across * phi.sin(), z), time))
+        }
+
+        fn shutter(&self) -> (f32, f32) {
+            (0., 0.)
+        }
+    }
+
+    const ALBEDO: f32 = 0.5;
+
+    fn furnace() -> Scene {
+        //// Inside a sphere which glows white everywhere and reflects half of what lands on it
+        Scene::new(vec![Box::new(Sphere {
+            centre: Vec3::new(0., 0., 0.),
+            radius: 1.,
+            texture: Arc::new(Colour::white()),
+            material: Material {
+                albedo: ALBEDO,
+                emission: Some(Emission::rgb(Colour::white(), 1.)),
+                ..LIGHT
+            },
+        })])
+    }
+
+    #[test]
+    fn furnace_gives_the_analytic_radiance() {
+        let scene = furnace();
+        let splats = Splats::new(1, 1);
+        let context = Context {scene: &scene, camera: &Eye, width: 1, height: 1, splats: &splats};
+        let mut bdpt = Bdpt::default();
+        bdpt.preprocess(&scene, 1);
+
+        let mut sampler = Independent::new(4839);
+        let mut rng = Pcg::new(4839, 0);
+        let samples = 20000;
+        let mut sum = 0.;
+        for i in 0..samples {
+            sampler.start_pixel_sample((0, 0), i);
+            let ray = Eye.generate_ray(sampler.get_2d(), sampler.get_2d(), 0.).unwrap();
+            sum += bdpt.li(ray, &context, &mut sampler, &mut rng, None).g();
+        }
+
+        // Light seen straight away, then after each bounce up to the most there can be
+        let expected: f32 = (0..=bdpt.max_depth).map(|bounces| ALBEDO.powi(bounces as i32)).sum();
+        let mean = sum / samples as f32;
+        assert!((mean - expected).abs() < 0.02 * expected, "{} should be {}", mean, expected);
+    }
+
+    #[test]
+    fn mis_weights_add_up_to_one() {
+        let scene = furnace();
+        let splats = Splats::new(1, 1);
+        let context = Context {scene: &scene, camera: &Eye, width: 1, height: 1, splats: &splats};
+        let mut bdpt = Bdpt::default();
+        bdpt.preprocess(&scene, 1);
+        let mut sampler = Independent::new(4839);
+
+        for length in 3..=6 {
+            for i in 0..20 {
+                // A whole path, from the camera to a point on the sphere treated as the light
+                sampler.start_pixel_sample((length, 0), i);
+                let ray = Eye.generate_ray(sampler.get_2d(), (0., 0.), 0.).unwrap();
+                let mut path = vec![Vertex::camera(ray.o, Colour::white())];
+                bdpt.random_walk(&context, ray, Colour::white(), 1., length as usize - 1, Mode::Radiance, &mut sampler, &mut path);
+                assert_eq!(path.len(), length as usize);
+
+                // How likely each vertex was to be made from either side
+                let n = path.len();
+                for j in 2..n {
+                    path[j].pdf_fwd = path[j - 1].pdf(&context, Some(&path[j - 2]), &path[j]);
+                }
+                path[n - 2].pdf_rev = path[n - 1].pdf_light(&path[n - 2]);
+                for j in (1..n - 2).rev() {
+                    path[j].pdf_rev = path[j + 1].pdf(&context, Some(&path[j + 2]), &path[j]);
+                }
+                path[n - 1].pdf_rev = bdpt.emitter_pdf(path[n - 1].id, &scene);
+
+                // Every way of splitting it between the camera and the light (the camera
+                // can't be joined to, so there are always at least two camera vertices)
+                let mut total = 0.;
+                for t in 2..=n {
+                    let s = n - t;
+                    let mut light: Vec<Vertex> = path[t..].iter().rev().map(|vertex| Vertex {
+                        pdf_fwd: vertex.pdf_rev,
+                        pdf_rev: vertex.pdf_fwd,
+                        ..*vertex
+                    }).collect();
+                    if let Some(first) = light.first_mut() {
+                        first.kind = Kind::Light;
+                    }
+                    let sampled = if s == 1 {Some(light[0])} else {None};
+                    let paths = Paths {light, camera: path[..t].to_vec(), time: 0., joinable: false};
+                    total += bdpt.mis_weight(&context, &paths, sampled, s, t);
+                }
+                assert!((total - 1.).abs() < 1e-3, "weights for a path of {} add up to {}", length, total);
+            }
+        }
+    }
+}
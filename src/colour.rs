@@ -11,13 +11,17 @@ const BRIGHTNESS: f32 = 2.;
 
 
 pub fn clamp(val: f32) -> f32 {
-    val.min(1.).max(0.)
+    val.clamp(0., 1.)
 }
 
 fn gamma_encode(linear: f32) -> f32 {
     linear.powf(1./GAMMA)
 }
 
+fn gamma_decode(encoded: f32) -> f32 {
+    encoded.powf(GAMMA)
+}
+
 fn exp(x: f32) -> f32 {
     //return x/4.;
     1. - (-x * BRIGHTNESS).exp()
@@ -25,6 +29,16 @@ fn exp(x: f32) -> f32 {
 
 pub type Colour = Vec3;
 
+pub fn heat(t: f32) -> Colour {
+    //// A colour for how much of something there is, from blue (none) through green to red (t = 1)
+    let t = clamp(t);
+    if t < 0.5 {
+        Colour::new(0., 2. * t, 1. - 2. * t)
+    } else {
+        Colour::new(2. * t - 1., 2. - 2. * t, 0.)
+    }
+}
+
 impl Colour {
     pub fn r(&self) -> f32 {
         self.value[0]
@@ -51,7 +65,33 @@ impl Colour {
             clamp(self.value[2]),)
     }
 
-    pub fn to_rgb(&self) -> Rgb<u8> {
+    pub fn luminance(&self) -> f32 {
+        //// How bright it looks (Rec. 709 weights)
+        0.2126 * self.r() + 0.7152 * self.g() + 0.0722 * self.b()
+    }
+
+    pub fn gamma_decode(&self) -> Colour {
+        Colour::new(gamma_decode(self.r()), gamma_decode(self.g()), gamma_decode(self.b()))
+    }
+
+    pub fn to_srgb(self) -> Rgb<u8> {
+        //// Gamma encoded but not tone mapped; for colours that are already in [0, 1]
+        let c = self.clamp();
+        Rgb::from_channels(
+            (gamma_encode(c.r()) * 255.) as u8,
+            (gamma_encode(c.g()) * 255.) as u8,
+            (gamma_encode(c.b()) * 255.) as u8,
+            0,
+        )
+    }
+
+    pub fn to_raw(self) -> Rgb<u8> {
+        //// Neither gamma encoded nor tone mapped; for values being shown as colours
+        let c = self.clamp();
+        Rgb::from_channels((c.r() * 255.) as u8, (c.g() * 255.) as u8, (c.b() * 255.) as u8, 0)
+    }
+
+    pub fn to_rgb(self) -> Rgb<u8> {
         Rgb::from_channels(
             (gamma_encode(exp(self.r())) * 255.) as u8,
             (gamma_encode(exp(self.g())) * 255.) as u8,
@@ -0,0 +1,159 @@
+use super::ray::*;
+use super::primitive::*;
+
+///////////////////////////////////////////
+//// The ways two solids can be combined
+///////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub enum Operation {
+    Union, // Inside either
+    Intersection, // Inside both
+    Difference, // Inside the left, but not the right
+}
+
+impl Operation {
+    fn inside(self, left: bool, right: bool) -> bool {
+        match self {
+            Operation::Union => left || right,
+            Operation::Intersection => left && right,
+            Operation::Difference => left && !right,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////
+//// Constructive solid geometry; two solids combined into
+//// a new one. It's a solid itself, so these can be nested
+////////////////////////////////////////////////////////////
+pub struct CSG {
+    pub left: Box<dyn Solid>,
+    pub right: Box<dyn Solid>,
+    pub operation: Operation,
+}
+
+impl Solid for CSG {
+    fn intervals(&self, ray: Ray) -> Vec<Span<'_>> {
+        // Every boundary crossing from either side, as
+        // (hit, whether it's going in, whether it's from the right)
+        let mut events: Vec<(SurfaceInteraction, bool, bool)> = vec![];
+
+        for Span(entry, exit) in self.left.intervals(ray) {
+            events.push((entry, true, false));
+            events.push((exit, false, false));
+        }
+        for Span(entry, exit) in self.right.intervals(ray) {
+            events.push((entry, true, true));
+            events.push((exit, false, true));
+        }
+
+        events.sort_by(|a, b| a.0.t.partial_cmp(&b.0.t).unwrap_or(std::cmp::Ordering::Equal));
+
+        // Walk along the line, keeping track of what we're inside;
+        // whenever that changes whether we're inside the result, it's a boundary
+        let mut spans = vec![];
+        let mut in_left = false;
+        let mut in_right = false;
+        let mut entry: Option<SurfaceInteraction> = None;
+
+        for (mut hit, going_in, from_right) in events {
+            let was_inside = self.operation.inside(in_left, in_right);
+
+            if from_right {
+                in_right = going_in;
+            } else {
+                in_left = going_in;
+            }
+
+            let inside = self.operation.inside(in_left, in_right);
+
+            if inside == was_inside {
+                continue;
+            }
+
+            // The carved out surfaces of a difference face the other way
+            if from_right {
+                if let Operation::Difference = self.operation {
+                    hit.flip();
+                }
+            }
+
+            if inside {
+                entry = Some(hit);
+            } else if let Some(start) = entry.take() {
+                spans.push(Span(start, hit));
+            }
+        }
+
+        spans
+    }
+}
+
+impl Object for CSG {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        nearest(&self.intervals(ray))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::vector::*;
+    use crate::colour::*;
+    use crate::material::*;
+
+    fn ball(x: f32) -> Box<Sphere> {
+        Box::new(Sphere {
+            centre: Vec3::new(x, 0., 0.),
+            radius: 1.,
+            texture: Arc::new(Colour::white()),
+            material: LAMBERT,
+        })
+    }
+
+    fn spans(operation: Operation) -> Vec<(f32, f32)> {
+        //// Where a ray along x goes through two overlapping balls; the
+        //// left one is from 4 to 6 along it, and the right from 5 to 7
+        let csg = CSG {left: ball(0.), right: ball(1.), operation};
+        let ray = Ray::new(Vec3::new(-5., 0., 0.), Vec3::new(1., 0., 0.), 0.);
+        csg.intervals(ray).iter().map(|Span(entry, exit)| (entry.t, exit.t)).collect()
+    }
+
+    fn close(spans: &[(f32, f32)], expected: &[(f32, f32)]) -> bool {
+        spans.len() == expected.len() && spans.iter().zip(expected)
+            .all(|(a, b)| (a.0 - b.0).abs() < 1e-4 && (a.1 - b.1).abs() < 1e-4)
+    }
+
+    #[test]
+    fn union() {
+        let spans = spans(Operation::Union);
+        assert!(close(&spans, &[(4., 7.)]), "{:?}", spans);
+    }
+
+    #[test]
+    fn intersection() {
+        let spans = spans(Operation::Intersection);
+        assert!(close(&spans, &[(5., 6.)]), "{:?}", spans);
+    }
+
+    #[test]
+    fn difference() {
+        let spans = spans(Operation::Difference);
+        assert!(close(&spans, &[(4., 5.)]), "{:?}", spans);
+    }
+
+    #[test]
+    fn difference_faces_out_of_the_hole() {
+        let csg = CSG {left: ball(0.), right: ball(1.), operation: Operation::Difference};
+        let ray = Ray::new(Vec3::new(-5., 0., 0.), Vec3::new(1., 0., 0.), 0.);
+        let Span(_, exit) = &csg.intervals(ray)[0];
+        assert!(exit.normal.x() > 0.99, "{:?}", exit.normal.x());
+    }
+
+    #[test]
+    fn separate() {
+        let csg = CSG {left: ball(0.), right: ball(3.), operation: Operation::Intersection};
+        let ray = Ray::new(Vec3::new(-5., 0., 0.), Vec3::new(1., 0., 0.), 0.);
+        assert!(csg.intervals(ray).is_empty());
+    }
+}
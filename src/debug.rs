@@ -0,0 +1,213 @@
+use super::vector::*;
+use super::colour::*;
+use super::primitive::*;
+use super::ray::*;
+use super::scene::*;
+use super::sampler::*;
+use super::rng::*;
+use super::aov::*;
+use super::integrator::*;
+
+////////////////////////////////////////////////////////////////////////
+//// Quick views for looking at a scene rather than lighting it; each
+//// shows one thing about whatever camera rays hit first, as a colour
+//// which is saved as it is rather than tone mapped. Nothing hit at all
+//// is black, other than for depth, where it's as far away as can be
+////////////////////////////////////////////////////////////////////////
+
+fn first_hit<'a>(context: &Context<'a>, ray: Ray, aov: Option<&mut AovSample>) -> Option<SurfaceInteraction<'a>> {
+    //// What a camera ray hits, with the AOVs that say what it is filled in
+    let hit = context.scene.intersect(ray)?;
+    if let Some(aov) = aov {
+        describe(aov, &hit, ray);
+    }
+    Some(hit)
+}
+
+fn describe(aov: &mut AovSample, hit: &SurfaceInteraction, ray: Ray) {
+    aov.depth = Some(hit.t * ray.d.length());
+    aov.normal = hit.shading;
+    aov.albedo = hit.colour * hit.material.albedo;
+    aov.object = Some(hit.id);
+    aov.material = Some(material_id(hit.material));
+}
+
+///////////////////////////////////////////////////////////////////////
+//// Ambient occlusion; white where a surface is open to everything
+//// around it, and darker the more of that is blocked (by anything
+//// closer than `distance`), like in creases and under objects
+///////////////////////////////////////////////////////////////////////
+pub struct AmbientOcclusion {
+    pub distance: f32,
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> AmbientOcclusion {
+        AmbientOcclusion {distance: 1.}
+    }
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, ray: Ray, context: &Context, sampler: &mut dyn Sampler, _: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        let Some(hit) = first_hit(context, ray, aov) else {return Colour::black()};
+
+        // One ray out from the side that was seen, more likely the more square on it is
+        let n = if dot(hit.shading, ray.d) < 0. {hit.shading} else {-hit.shading};
+        let out = offset(&hit, cosine_sample(n, sampler.get_2d()), ray.time);
+        if context.scene.intersect(out).is_some_and(|blocker| blocker.t < self.distance) {
+            return Colour::black();
+        }
+        Colour::white()
+    }
+
+    fn tone_mapped(&self) -> bool {
+        false
+    }
+}
+
+//////////////////////////////////////////////////////////////////
+//// Shading normals (with any normal or bump maps) in world space,
+//// as colours like the normal AOV
+//////////////////////////////////////////////////////////////////
+pub struct ShowNormals;
+
+impl Integrator for ShowNormals {
+    fn li(&self, ray: Ray, context: &Context, _: &mut dyn Sampler, _: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        match first_hit(context, ray, aov) {
+            Some(hit) => (hit.shading + Vec3::new(1., 1., 1.)) * 0.5,
+            None => Colour::black(),
+        }
+    }
+
+    fn tone_mapped(&self) -> bool {
+        false
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+//// How far away the first thing hit is, from black (right at the
+//// camera) to white (`far` away or more)
+//////////////////////////////////////////////////////////////////////
+pub struct ShowDepth {
+    pub far: f32,
+}
+
+impl Default for ShowDepth {
+    fn default() -> ShowDepth {
+        ShowDepth {far: 10.}
+    }
+}
+
+impl Integrator for ShowDepth {
+    fn li(&self, ray: Ray, context: &Context, _: &mut dyn Sampler, _: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        match first_hit(context, ray, aov) {
+            Some(hit) => Colour::white() * clamp(hit.t * ray.d.length() / self.far),
+            None => Colour::white(),
+        }
+    }
+
+    fn tone_mapped(&self) -> bool {
+        false
+    }
+}
+
+///////////////////////////////////////////////////////////////////////
+//// Texture coordinates; u as red and v as green. Some shapes' uvs are
+//// distances along them, so they wrap round every 1, like textures do
+///////////////////////////////////////////////////////////////////////
+pub struct ShowUv;
+
+impl Integrator for ShowUv {
+    fn li(&self, ray: Ray, context: &Context, _: &mut dyn Sampler, _: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        match first_hit(context, ray, aov) {
+            Some(hit) => Colour::new(hit.uv.0.rem_euclid(1.), hit.uv.1.rem_euclid(1.), 0.),
+            None => Colour::black(),
+        }
+    }
+
+    fn tone_mapped(&self) -> bool {
+        false
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+//// How much work finding what a camera ray hits takes, from blue (none)
+//// through green to red (`most` or more); every object tried counts one,
+//// as does every step a marched shape takes. Every object is tried for
+//// every ray, so this shows which are slow to hit, like marched shapes
+//// and fractals. It's counted rather than timed, so it's the same each run
+//////////////////////////////////////////////////////////////////////////
+pub struct ShowCost {
+    pub most: f32,
+}
+
+impl Default for ShowCost {
+    fn default() -> ShowCost {
+        ShowCost {most: 100.}
+    }
+}
+
+impl Integrator for ShowCost {
+    fn li(&self, ray: Ray, context: &Context, _: &mut dyn Sampler, _: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        let before = work_done();
+        let hit = context.scene.intersect(ray);
+        let work = (work_done() - before) as f32;
+
+        if let (Some(aov), Some(hit)) = (aov, &hit) {
+            describe(aov, hit, ray);
+        }
+        heat(work / self.most)
+    }
+
+    fn tone_mapped(&self) -> bool {
+        false
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+//// Whitted's ray tracer; mirrors and glass are followed, and diffuse
+//// surfaces are lit straight from the point, spot and directional lights
+//// (with shadows), but not by anything else. For a rough idea of light
+//// from the sky too, they get whatever of it is in the direction they face
+///////////////////////////////////////////////////////////////////////////
+pub struct Whitted {
+    pub depth: u32,
+}
+
+impl Default for Whitted {
+    fn default() -> Whitted {
+        Whitted {depth: 8}
+    }
+}
+
+impl Whitted {
+    fn trace(&self, ray: Ray, scene: &Scene, depth: u32, sampler: &mut dyn Sampler, rng: &mut Pcg) -> Colour {
+        if depth == 0 {
+            return Colour::black();
+        }
+        let d = ray.d.normalise();
+        let Some(hit) = scene.intersect(ray) else {return scene.background.radiance(d)};
+        let mat = hit.material;
+        let emitted = mat.emission.as_ref().map_or(Colour::black(), |emission| emission.radiance(hit.uv, hit.point, hit.front_face));
+
+        if mat.diffuse > 0. {
+            let facing = if hit.front_face {hit.shading} else {-hit.shading};
+            let sky = hit.colour * scene.background.radiance(facing) * (mat.albedo * mat.diffuse);
+            return emitted + direct_light(scene, &hit, ray.time, sampler, rng) + sky;
+        }
+
+        let (newd, factor) = bounce(&hit, d, sampler);
+        emitted + factor * self.trace(offset(&hit, newd, ray.time), scene, depth - 1, sampler, rng)
+    }
+}
+
+impl Integrator for Whitted {
+    fn li(&self, ray: Ray, context: &Context, sampler: &mut dyn Sampler, rng: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        if let Some(aov) = aov {
+            if let Some(hit) = context.scene.intersect(ray) {
+                describe(aov, &hit, ray);
+            }
+        }
+        self.trace(ray, context.scene, self.depth, sampler, rng)
+    }
+}
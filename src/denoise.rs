@@ -0,0 +1,169 @@
+use std::thread;
+use super::vector::*;
+use super::colour::*;
+use super::aov::*;
+
+//////////////////////////////////////////////////////////////////////////
+//// Smooths the noise out of a finished (linear) picture with an edge-
+//// avoiding à-trous wavelet filter (Dammertz et al.), as in SVGF. Each
+//// pass blurs with a 5x5 kernel, spread twice as far as the last one,
+//// but pixels only blur together if their normals, depths and albedos
+//// match, and their brightnesses are no further apart than the noise
+//// (the variance) says they could be by chance. The albedo is divided
+//// out first, so textures stay sharp, and multiplied back in at the end
+//////////////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct Denoiser {
+    pub iterations: u32, // Reaching 2^iterations pixels away, roughly
+    pub sigma_colour: f32, // How many standard deviations apart brightnesses can be
+    pub sigma_normal: f32, // Power of the normals' dot product; higher keeps corners sharper
+    pub sigma_depth: f32, // Relative difference in depth allowed, per pixel apart
+    pub sigma_albedo: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            sigma_colour: 4.,
+            sigma_normal: 64.,
+            sigma_depth: 0.02,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+// B3 spline, which each pass's kernel is the outer product of
+const KERNEL: [f32; 5] = [1./16., 1./4., 3./8., 1./4., 1./16.];
+
+// Guides for each pixel, from the AOVs
+struct Guide {
+    normal: Vec3,
+    depth: Option<f32>,
+    albedo: Colour,
+}
+
+fn demodulator(albedo: Colour) -> Colour {
+    //// What to divide a pixel by to leave only its lighting; nothing where there's no albedo
+    if albedo.luminance() <= 0. {
+        return Colour::white();
+    }
+    Colour::new(albedo.r().max(0.01), albedo.g().max(0.01), albedo.b().max(0.01))
+}
+
+fn divide(a: Colour, b: Colour) -> Colour {
+    Colour::new(a.r() / b.r(), a.g() / b.g(), a.b() / b.b())
+}
+
+impl Denoiser {
+    pub fn denoise(&self, pixels: &[Colour], variance: &[f32], aovs: &Aovs, threads: usize) -> Vec<Colour> {
+        //// Denoise a picture, given the variance of each pixel's brightness
+        let (width, height) = (aovs.width as usize, aovs.height as usize);
+
+        let guides: Vec<Guide> = (0..width * height).map(|i| {
+            let pixel = aovs.pixel((i % width) as u32, (i / width) as u32);
+            Guide {normal: pixel.normal(), depth: pixel.depth(), albedo: pixel.albedo()}
+        }).collect();
+
+        // Only the lighting gets filtered; the variance shrinks or grows with it
+        let demodulators: Vec<Colour> = guides.iter().map(|guide| demodulator(guide.albedo)).collect();
+        let mut colour: Vec<Colour> = pixels.iter().zip(&demodulators).map(|(col, d)| divide(*col, *d)).collect();
+        let mut variance: Vec<f32> = variance.iter().zip(&demodulators)
+            .map(|(var, d)| var / d.luminance().powi(2)).collect();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let blurred = blur_variance(&variance, width, height);
+
+            let mut next_colour = vec![Colour::black(); width * height];
+            let mut next_variance = vec![0.; width * height];
+            let rows_each = height.div_ceil(threads.max(1));
+
+            thread::scope(|scope| {
+                let chunks = next_colour.chunks_mut(rows_each * width).zip(next_variance.chunks_mut(rows_each * width));
+                for (chunk, (colour_out, variance_out)) in chunks.enumerate() {
+                    let (colour, variance, blurred, guides) = (&colour, &variance, &blurred, &guides);
+                    scope.spawn(move || {
+                        for (i, (col, var)) in colour_out.iter_mut().zip(variance_out.iter_mut()).enumerate() {
+                            let p = chunk * rows_each * width + i;
+                            (*col, *var) = self.filter_pixel(p, step, width, height, colour, variance, blurred, guides);
+                        }
+                    });
+                }
+            });
+
+            colour = next_colour;
+            variance = next_variance;
+        }
+
+        colour.iter().zip(&demodulators).map(|(col, d)| *col * *d).collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn filter_pixel(&self, p: usize, step: i64, width: usize, height: usize, colour: &[Colour], variance: &[f32],
+            blurred: &[f32], guides: &[Guide]) -> (Colour, f32) {
+        //// One pixel of one pass; the weighted average of the pixels around it
+        let (x, y) = ((p % width) as i64, (p / width) as i64);
+        let centre = &guides[p];
+        let luminance = colour[p].luminance();
+        let deviation = blurred[p].max(0.).sqrt();
+
+        let (mut sum, mut sum_variance, mut total) = (Colour::black(), 0., 0.);
+
+        for (j, ky) in KERNEL.iter().enumerate() {
+            for (i, kx) in KERNEL.iter().enumerate() {
+                let (qx, qy) = (x + (i as i64 - 2) * step, y + (j as i64 - 2) * step);
+                if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                    continue;
+                }
+                let q = qy as usize * width + qx as usize;
+                let other = &guides[q];
+
+                let w_normal = dot(centre.normal, other.normal).max(0.).powf(self.sigma_normal);
+                // Nothing hit is no normal at all; those only match each other
+                let w_normal = if centre.normal.length_squared() == 0. && other.normal.length_squared() == 0. {1.} else {w_normal};
+
+                let w_depth = match (centre.depth, other.depth) {
+                    (Some(a), Some(b)) => {
+                        let apart = ((qx - x).abs().max((qy - y).abs())) as f32;
+                        (-(a - b).abs() / (self.sigma_depth * a.max(1e-3) * apart.max(1.))).exp()
+                    },
+                    (None, None) => 1.,
+                    _ => 0.,
+                };
+
+                let albedo = centre.albedo - other.albedo;
+                let w_albedo = (-albedo.length_squared() / (self.sigma_albedo * self.sigma_albedo)).exp();
+
+                let w_colour = (-(luminance - colour[q].luminance()).abs() / (self.sigma_colour * deviation + 1e-4)).exp();
+
+                let w = kx * ky * w_normal * w_depth * w_albedo * w_colour;
+                sum += colour[q] * w;
+                sum_variance += w * w * variance[q];
+                total += w;
+            }
+        }
+
+        // The centre always has some weight, so this can't be zero
+        (sum / total, sum_variance / (total * total))
+    }
+}
+
+fn blur_variance(variance: &[f32], width: usize, height: usize) -> Vec<f32> {
+    //// A small (3x3) Gaussian blur, since the variance of one pixel is itself noisy
+    const WEIGHTS: [f32; 3] = [0.25, 0.5, 0.25];
+    (0..width * height).map(|p| {
+        let (x, y) = ((p % width) as i64, (p / width) as i64);
+        let (mut sum, mut total) = (0., 0.);
+        for (j, wy) in WEIGHTS.iter().enumerate() {
+            for (i, wx) in WEIGHTS.iter().enumerate() {
+                let (qx, qy) = (x + i as i64 - 1, y + j as i64 - 1);
+                if qx >= 0 && qy >= 0 && qx < width as i64 && qy < height as i64 {
+                    sum += wx * wy * variance[qy as usize * width + qx as usize];
+                    total += wx * wy;
+                }
+            }
+        }
+        sum / total
+    }).collect()
+}
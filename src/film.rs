@@ -0,0 +1,292 @@
+use image::{ImageBuffer, RgbImage};
+use std::sync::atomic::{AtomicU64, Ordering};
+use super::vector::*;
+use super::colour::*;
+
+///////////////////////////////////////////////////////////////////////
+//// How much a sample counts towards a pixel, by how far (in pixels)
+//// it landed from the pixel's centre. Mitchell and Lanczos go a bit
+//// negative in places, which keeps edges sharp
+///////////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub enum Filter {
+    Box { radius: f32 }, // Radius 0.5 is a plain average over each pixel
+    Tent { radius: f32 },
+    Gaussian { radius: f32, alpha: f32 }, // Bigger alpha falls off faster
+    Mitchell { radius: f32, b: f32, c: f32 }, // Mitchell and Netravali suggest b = c = 1/3
+    Lanczos { radius: f32 }, // Sinc, windowed by a sinc stretched out to the radius
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        return 1.;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+fn mitchell(x: f32, b: f32, c: f32) -> f32 {
+    //// The Mitchell-Netravali cubic, which reaches out to 2
+    let x = x.abs();
+    let value = if x < 1. {
+        (12. - 9. * b - 6. * c) * x*x*x + (-18. + 12. * b + 6. * c) * x*x + (6. - 2. * b)
+    } else if x < 2. {
+        (-b - 6. * c) * x*x*x + (6. * b + 30. * c) * x*x + (-12. * b - 48. * c) * x + (8. * b + 24. * c)
+    } else {
+        0.
+    };
+    value / 6.
+}
+
+impl Filter {
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box {radius} | Filter::Tent {radius} | Filter::Gaussian {radius, ..}
+                | Filter::Mitchell {radius, ..} | Filter::Lanczos {radius} => radius,
+        }
+    }
+
+    fn weight_1d(&self, x: f32) -> f32 {
+        match *self {
+            Filter::Box {radius} => if x.abs() <= radius {1.} else {0.},
+            Filter::Tent {radius} => (radius - x.abs()).max(0.),
+            Filter::Gaussian {radius, alpha} => ((-alpha * x*x).exp() - (-alpha * radius*radius).exp()).max(0.),
+            Filter::Mitchell {radius, b, c} => mitchell(2. * x / radius, b, c),
+            Filter::Lanczos {radius} => if x.abs() < radius {sinc(x) * sinc(x / radius)} else {0.},
+        }
+    }
+
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        //// Filters are separable; the weight is the same across each axis, multiplied
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//// Some rows of the film, which samples near one row get splatted
+//// into before being added to the film (so rows can be rendered
+//// separately, and added in the same order every time)
+/////////////////////////////////////////////////////////////////////
+pub struct Tile {
+    top: u32, // First row covered
+    rows: u32,
+    width: u32,
+    filter: Filter,
+    sum: Vec<Colour>, // Weighted colours
+    weight: Vec<f32>,
+}
+
+impl Tile {
+    pub fn new(row: u32, width: u32, height: u32, filter: Filter) -> Tile {
+        //// Somewhere to put the samples from one row, covering
+        //// every row the filter can reach from it
+        let reach = (filter.radius() + 0.5).ceil() as u32;
+        let top = row.saturating_sub(reach);
+        let rows = (row + reach + 1).min(height) - top;
+        let size = (rows * width) as usize;
+
+        Tile {
+            top,
+            rows,
+            width,
+            filter,
+            sum: vec![Colour::black(); size],
+            weight: vec![0.; size],
+        }
+    }
+
+    pub fn add_sample(&mut self, (x, y): (f32, f32), col: Colour) {
+        //// Add a sample at some point (in pixels) to every pixel
+        //// it's near enough to for the filter to reach
+        let radius = self.filter.radius();
+        let bottom = (self.top + self.rows) as i64 - 1;
+
+        let x0 = ((x - 0.5 - radius).ceil() as i64).max(0);
+        let x1 = ((x - 0.5 + radius).floor() as i64).min(self.width as i64 - 1);
+        let y0 = ((y - 0.5 - radius).ceil() as i64).max(self.top as i64);
+        let y1 = ((y - 0.5 + radius).floor() as i64).min(bottom);
+
+        for py in y0..=y1 {
+            for px in x0..=x1 {
+                let w = self.filter.weight(x - (px as f32 + 0.5), y - (py as f32 + 0.5));
+                if w == 0. {
+                    continue;
+                }
+                let i = (py as u32 - self.top) as usize * self.width as usize + px as usize;
+                self.sum[i] += col * w;
+                self.weight[i] += w;
+            }
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////
+//// The picture being built up, as a filtered weighted sum
+//// of every sample near each pixel
+/////////////////////////////////////////////////////////////
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    sum: Vec<Colour>,
+    weight: Vec<f32>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        let size = (width * height) as usize;
+        Film {
+            width,
+            height,
+            sum: vec![Colour::black(); size],
+            weight: vec![0.; size],
+        }
+    }
+
+    pub fn merge(&mut self, tile: Tile) {
+        let start = (tile.top * self.width) as usize;
+        for (i, (sum, weight)) in tile.sum.into_iter().zip(tile.weight).enumerate() {
+            self.sum[start + i] += sum;
+            self.weight[start + i] += weight;
+        }
+    }
+
+    pub fn colour(&self, x: u32, y: u32) -> Colour {
+        //// Filters with negative lobes can leave a pixel with next to no weight, which
+        //// would blow up, or with negative light, which there's no such thing as
+        let i = (y * self.width + x) as usize;
+        if self.weight[i] > 1e-4 {
+            (self.sum[i] / self.weight[i]).max(0.)
+        } else {
+            Colour::black()
+        }
+    }
+
+    pub fn pixels(&self) -> Vec<Colour> {
+        (0..self.width * self.height).map(|i| self.colour(i % self.width, i / self.width)).collect()
+    }
+
+    pub fn set_pixels(&mut self, pixels: Vec<Colour>) {
+        //// Replace the picture with a finished one (like after denoising)
+        self.weight = vec![1.; pixels.len()];
+        self.sum = pixels;
+    }
+
+    pub fn add_splats(&mut self, splats: &Splats, scale: f32) {
+        //// Add light traced to the camera on top of what the camera saw
+        for i in 0..self.sum.len() {
+            let splat = splats.colour(i) * scale;
+            if self.weight[i] > 0. {
+                self.sum[i] += splat * self.weight[i];
+            } else {
+                self.sum[i] = splat;
+                self.weight[i] = 1.;
+            }
+        }
+    }
+
+    pub fn image(&self, tone_mapped: bool) -> RgbImage {
+        //// The finished picture; tone mapped if it's light, or as it is if it's something else shown as colours
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let col = self.colour(x, y).clamp();
+            if tone_mapped {col.to_rgb()} else {col.to_raw()}
+        })
+    }
+}
+
+// Splats are kept in fixed point with this many steps to 1; the biggest
+// one allowed still leaves room for millions of them to add up in a pixel
+const SPLAT_SCALE: f64 = (1u64 << 24) as f64;
+const BIGGEST_SPLAT: f32 = 1e4;
+
+//////////////////////////////////////////////////////////////////////////
+//// Light traced from the lights into the camera, which can land on any
+//// pixel at all, from any row being rendered. It's added up as integers,
+//// so it comes to the same whichever order the threads get to it in
+//////////////////////////////////////////////////////////////////////////
+pub struct Splats {
+    width: u32,
+    height: u32,
+    sum: Vec<AtomicU64>, // Three to a pixel
+}
+
+impl Splats {
+    pub fn new(width: u32, height: u32) -> Splats {
+        Splats {width, height, sum: (0..width * height * 3).map(|_| AtomicU64::new(0)).collect()}
+    }
+
+    pub fn add(&self, (x, y): (f32, f32), col: Colour) {
+        //// Add to whichever pixel a point (in pixels) is in
+        if x < 0. || y < 0. || x >= self.width as f32 || y >= self.height as f32 {
+            return;
+        }
+        let i = (y as u32 * self.width + x as u32) as usize * 3;
+        for (channel, value) in col.value.iter().enumerate() {
+            if value.is_finite() && *value > 0. {
+                let fixed = (value.min(BIGGEST_SPLAT) as f64 * SPLAT_SCALE) as u64;
+                self.sum[i + channel].fetch_add(fixed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn colour(&self, pixel: usize) -> Colour {
+        let channel = |c: usize| (self.sum[pixel * 3 + c].load(Ordering::Relaxed) as f64 / SPLAT_SCALE) as f32;
+        Colour::new(channel(0), channel(1), channel(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 5] = [
+        Filter::Box {radius: 0.5},
+        Filter::Tent {radius: 1.},
+        Filter::Gaussian {radius: 1.5, alpha: 2.},
+        Filter::Mitchell {radius: 2., b: 1./3., c: 1./3.},
+        Filter::Lanczos {radius: 3.},
+    ];
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn weights_in_the_middle() {
+        let middle: Vec<f32> = FILTERS.iter().map(|filter| filter.weight(0., 0.)).collect();
+        let gaussian = 1. - (-2. * 1.5f32 * 1.5).exp();
+        let expected = [1., 1., gaussian * gaussian, (16. / 18.) * (16. / 18.), 1.];
+        assert!(middle.iter().zip(expected).all(|(&a, b)| close(a, b)), "{:?}", middle);
+    }
+
+    #[test]
+    fn weights_at_the_radius() {
+        for filter in FILTERS {
+            let r = filter.radius();
+            // The box takes in its edge; everything else has faded out by then
+            let edge = if let Filter::Box {..} = filter {1.} else {0.};
+            assert!(close(filter.weight(r, 0.), edge));
+            assert!(close(filter.weight(0., -r), edge));
+            assert_eq!(filter.weight(r + 0.01, 0.), 0.);
+            assert_eq!(filter.weight(0., -r - 0.01), 0.);
+        }
+    }
+
+    #[test]
+    fn weights_are_symmetric() {
+        for filter in FILTERS {
+            for x in [0.1, 0.3, 0.45, 0.9, 1.7, 2.5] {
+                assert_eq!(filter.weight(x, 0.2), filter.weight(-x, -0.2));
+                assert_eq!(filter.weight(x, 0.2), filter.weight(0.2, x));
+            }
+        }
+    }
+
+    #[test]
+    fn next_to_no_weight_is_black() {
+        let mut film = Film::new(2, 1);
+        film.sum = vec![Colour::new(1., 1., 1.), Colour::new(-0.5, 2., 1.)];
+        film.weight = vec![1e-6, 1.];
+        assert_eq!(film.colour(0, 0).max_element(), 0.);
+        let colour = film.colour(1, 0);
+        assert_eq!((colour.x(), colour.y(), colour.z()), (0., 2., 1.));
+    }
+}
@@ -0,0 +1,223 @@
+use super::vector::*;
+use super::colour::*;
+
+////////////////////////////////////////////////////////////
+//// The fractals there are distance estimators for, and
+//// whatever's particular to each of them
+////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub enum Estimator {
+    Mandelbulb { power: f32, bailout: f32 },
+    Mandelbox { scale: f32, min_radius: f32, fixed_radius: f32, folding_limit: f32 },
+    Menger,
+    Sierpinski { scale: f32 },
+    Julia { c: [f32; 4], bailout: f32 }, // c is a quaternion; the set is sliced at w = 0
+    // Kaleidoscopic IFS: fold into one octant, rotate, then scale away from the offset
+    Kifs { scale: f32, offset: Vec3, axis: Vec3, angle: f32 },
+}
+
+///////////////////////////////////////////////////////////////////
+//// A fractal distance estimator. Colours come from an orbit trap;
+//// points whose orbit comes close to the origin get `near`, ones
+//// that stay a unit or more away get `far`
+///////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct Fractal {
+    pub estimator: Estimator,
+    pub iterations: u32,
+    pub near: Colour,
+    pub far: Colour,
+}
+
+impl Fractal {
+    pub fn new(estimator: Estimator) -> Fractal {
+        //// Fairly standard settings which give a nice looking version of each
+        let iterations = match estimator {
+            Estimator::Menger => 5,
+            Estimator::Sierpinski {..} => 12,
+            _ => 10,
+        };
+
+        Fractal {
+            estimator,
+            iterations,
+            near: Colour::new(0.9, 0.5, 0.2),
+            far: Colour::white(),
+        }
+    }
+
+    pub fn distance(&self, p: Vec3) -> f32 {
+        self.estimate(p).0
+    }
+
+    pub fn colour(&self, p: Vec3) -> Colour {
+        let trap = clamp(self.estimate(p).1);
+        self.near * (1. - trap) + self.far * trap
+    }
+
+    fn estimate(&self, p: Vec3) -> (f32, f32) {
+        //// The distance, and how close the orbit came to the origin
+        match self.estimator {
+            Estimator::Mandelbulb {power, bailout} => mandelbulb(p, self.iterations, power, bailout),
+            Estimator::Mandelbox {scale, min_radius, fixed_radius, folding_limit} =>
+                mandelbox(p, self.iterations, scale, min_radius, fixed_radius, folding_limit),
+            Estimator::Menger => menger(p, self.iterations),
+            Estimator::Sierpinski {scale} => sierpinski(p, self.iterations, scale),
+            Estimator::Julia {c, bailout} => julia(p, self.iterations, c, bailout),
+            Estimator::Kifs {scale, offset, axis, angle} => kifs(p, self.iterations, scale, offset, axis, angle),
+        }
+    }
+}
+
+fn mandelbulb(pos: Vec3, iterations: u32, power: f32, bailout: f32) -> (f32, f32) {
+    let mut z = pos;
+    let mut dr = 1.;
+    let mut r: f32 = 0.;
+    let mut trap = f32::INFINITY;
+
+    for _ in 0..iterations {
+        r = z.length();
+        trap = trap.min(r);
+        if r > bailout {break;}
+
+        let mut theta = (z.z()/ r).acos();
+        let mut phi = (z.y()).atan2(z.x());
+        dr = r.powf(power - 1.) * power * dr + 1.;
+
+        let zr = r.powf(power);
+        theta *= power;
+        phi *= power;
+
+        z = zr * Vec3::new(theta.sin()*phi.cos(), phi.sin() * theta.sin(), theta.cos());
+        z += pos;
+    }
+
+    (0.5 * r.ln() * r/dr, trap)
+}
+
+fn mandelbox(pos: Vec3, iterations: u32, scale: f32, min_radius: f32, fixed_radius: f32, folding_limit: f32) -> (f32, f32) {
+    let min_r2 = min_radius * min_radius;
+    let fixed_r2 = fixed_radius * fixed_radius;
+
+    let mut z = pos;
+    let mut dr = 1.;
+    let mut trap = f32::INFINITY;
+
+    for _ in 0..iterations {
+        // Box fold
+        for i in 0..3 {
+            z.value[i] = z.value[i].clamp(-folding_limit, folding_limit) * 2. - z.value[i];
+        }
+
+        // Sphere fold
+        let r2 = dot(z, z);
+        if r2 < min_r2 {
+            z = z * (fixed_r2 / min_r2);
+            dr *= fixed_r2 / min_r2;
+        } else if r2 < fixed_r2 {
+            z = z * (fixed_r2 / r2);
+            dr *= fixed_r2 / r2;
+        }
+
+        z = scale * z + pos;
+        dr = dr * scale.abs() + 1.;
+        trap = trap.min(z.length());
+    }
+
+    (z.length() / dr.abs(), trap)
+}
+
+fn menger(pos: Vec3, iterations: u32) -> (f32, f32) {
+    // Start with a cube, and keep carving crosses out of it at smaller scales
+    let q = pos.abs() - Vec3::new(1., 1., 1.);
+    let mut d = q.max(0.).length() + q.max_element().min(0.);
+    let mut s = 1.;
+    let mut trap = f32::INFINITY;
+
+    for _ in 0..iterations {
+        let a = pos * s;
+        let a = Vec3::new(a.x().rem_euclid(2.) - 1., a.y().rem_euclid(2.) - 1., a.z().rem_euclid(2.) - 1.);
+        s *= 3.;
+        let r = (Vec3::new(1., 1., 1.) - 3. * a.abs()).abs();
+
+        let da = r.x().max(r.y());
+        let db = r.y().max(r.z());
+        let dc = r.z().max(r.x());
+        let c = (da.min(db).min(dc) - 1.) / s;
+
+        d = d.max(c);
+        trap = trap.min(a.length());
+    }
+
+    (d, trap)
+}
+
+fn sierpinski(pos: Vec3, iterations: u32, scale: f32) -> (f32, f32) {
+    // Fold towards the corner at (1, 1, 1), and scale away from it
+    let mut z = pos;
+    let mut trap = f32::INFINITY;
+
+    for _ in 0..iterations {
+        if z.x() + z.y() < 0. { z = Vec3::new(-z.y(), -z.x(), z.z()); }
+        if z.x() + z.z() < 0. { z = Vec3::new(-z.z(), z.y(), -z.x()); }
+        if z.y() + z.z() < 0. { z = Vec3::new(z.x(), -z.z(), -z.y()); }
+
+        z = z * scale - Vec3::new(1., 1., 1.) * (scale - 1.);
+        trap = trap.min(z.length());
+    }
+
+    (z.length() * scale.powi(-(iterations as i32)), trap)
+}
+
+fn quaternion_mul(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0]*b[0] - a[1]*b[1] - a[2]*b[2] - a[3]*b[3],
+        a[0]*b[1] + a[1]*b[0] + a[2]*b[3] - a[3]*b[2],
+        a[0]*b[2] - a[1]*b[3] + a[2]*b[0] + a[3]*b[1],
+        a[0]*b[3] + a[1]*b[2] - a[2]*b[1] + a[3]*b[0],
+    ]
+}
+
+fn quaternion_length(a: [f32; 4]) -> f32 {
+    (a[0]*a[0] + a[1]*a[1] + a[2]*a[2] + a[3]*a[3]).sqrt()
+}
+
+fn julia(pos: Vec3, iterations: u32, c: [f32; 4], bailout: f32) -> (f32, f32) {
+    let mut z = [pos.x(), pos.y(), pos.z(), 0.];
+    let mut dz = [1., 0., 0., 0.];
+    let mut trap = f32::INFINITY;
+
+    for _ in 0..iterations {
+        // z' = 2z z', z = z^2 + c
+        dz = quaternion_mul(z, dz);
+        dz = [2. * dz[0], 2. * dz[1], 2. * dz[2], 2. * dz[3]];
+        z = quaternion_mul(z, z);
+        z = [z[0] + c[0], z[1] + c[1], z[2] + c[2], z[3] + c[3]];
+
+        let r = quaternion_length(z);
+        trap = trap.min(r);
+        if r > bailout {break;}
+    }
+
+    let r = quaternion_length(z);
+    (0.5 * r * r.ln() / quaternion_length(dz), trap)
+}
+
+fn kifs(pos: Vec3, iterations: u32, scale: f32, offset: Vec3, axis: Vec3, angle: f32) -> (f32, f32) {
+    let mut z = pos;
+    let mut trap = f32::INFINITY;
+
+    for _ in 0..iterations {
+        // Fold into the octant with x >= y >= z >= 0
+        z = z.abs();
+        if z.x() < z.y() { z = Vec3::new(z.y(), z.x(), z.z()); }
+        if z.x() < z.z() { z = Vec3::new(z.z(), z.y(), z.x()); }
+        if z.y() < z.z() { z = Vec3::new(z.x(), z.z(), z.y()); }
+
+        z = rotate(z, axis, angle);
+        z = z * scale - offset * (scale - 1.);
+        trap = trap.min(z.length());
+    }
+
+    ((z.length() - 1.) * scale.powi(-(iterations as i32)), trap)
+}
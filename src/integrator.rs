@@ -0,0 +1,222 @@
+use super::vector::*;
+use super::colour::*;
+use super::primitive::*;
+use super::ray::*;
+use super::medium::*;
+use super::scene::*;
+use super::sampler::*;
+use super::rng::*;
+use super::film::*;
+use super::light::*;
+use super::aov::*;
+use super::render::*;
+
+///////////////////////////////////////////////////////////////////////
+//// What an integrator has to work with for each camera ray; the scene,
+//// and the camera and film too, for light traced back into the camera
+//// which can land on any pixel (that gets splatted onto the film)
+///////////////////////////////////////////////////////////////////////
+pub struct Context<'a> {
+    pub scene: &'a Scene,
+    pub camera: &'a dyn Camera,
+    pub width: u32,
+    pub height: u32,
+    pub splats: &'a Splats,
+}
+
+impl Context<'_> {
+    pub fn aspect(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    pub fn raster(&self, (u, v): (f32, f32)) -> (f32, f32) {
+        //// Film coordinates in pixels (the opposite of what render_row does)
+        let (width, height) = (self.width as f32, self.height as f32);
+        ((u * height + width) / 2., (height - v * height) / 2.)
+    }
+}
+
+/////////////////////////////////////////////////////////////////
+//// A way of working out how much light comes back along a ray
+/////////////////////////////////////////////////////////////////
+pub trait Integrator: Send + Sync {
+    // Anything worked out once for the whole picture, before rendering starts,
+    // with as many threads as the render has
+    fn preprocess(&mut self, _: &Scene, _threads: usize) {}
+
+    // Surfaces take their random numbers from the sampler; volumes, which can
+    // need any number of them, from rng. If there's an AOV sample, it's filled
+    // in with what this ray hit first
+    fn li(&self, ray: Ray, context: &Context, sampler: &mut dyn Sampler, rng: &mut Pcg, aov: Option<&mut AovSample>) -> Colour;
+
+    // Whether what it gives is light, which gets tone mapped; views for debugging
+    // give values from 0 to 1 which are saved as they are
+    fn tone_mapped(&self) -> bool {
+        true
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+//// The original path tracer; follows the material's sampled direction
+//// at each surface, with the (point, spot and directional) lights added
+//// straight onto diffuse surfaces
+////////////////////////////////////////////////////////////////////////
+pub struct PathTracer {
+    pub depth: u32,
+}
+
+impl Default for PathTracer {
+    fn default() -> PathTracer {
+        PathTracer {depth: 4}
+    }
+}
+
+impl Integrator for PathTracer {
+    fn li(&self, ray: Ray, context: &Context, sampler: &mut dyn Sampler, rng: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        trace(ray, context.scene, self.depth, sampler, rng, aov)
+    }
+}
+
+fn trace(ray: Ray, scene: &Scene, depth: u32, sampler: &mut dyn Sampler, rng: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+    //// Raytrace a whole scene
+    if depth == 0 {
+        // Return white if the raytracing depth is reached
+        // Originally this returned error red; now it returns white
+        return Colour::white();
+    }
+
+    let d = ray.d;
+    let hit = scene.intersect(ray);
+
+    // See if the ray gets scattered by anything before reaching the surface;
+    // if several volumes overlap, whichever it happens in first
+    let t_max = hit.as_ref().map_or(f32::INFINITY, |hit| hit.t);
+    let mut scatter: Option<(Collision, f32)> = None;
+
+    for volume in scene.volumes.iter() {
+        if let Some(collision) = volume.sample(ray, t_max, rng) {
+            if scatter.is_none_or(|(prev, _)| collision.t < prev.t) {
+                scatter = Some((collision, volume.medium.anisotropy()));
+            }
+        }
+    }
+
+    if let Some((collision, g)) = scatter {
+        // Whatever isn't scattered is absorbed, and that's where the medium glows from
+        let p = ray.at(collision.t);
+        let d = d.normalise();
+
+        // Light from the lights scattered towards where the ray came from; the
+        // sampler's dimensions line up with surface bounces, so this uses the rng
+        let mut direct = Colour::black();
+        for light in scene.lights.iter() {
+            if let Some(sample) = light.sample(p, (rng.next_float(), rng.next_float())) {
+                let phase = henyey_greenstein_phase(dot(d, sample.direction), g);
+                direct += sample.radiance * phase * shadow(scene, p, &sample, ray.time, rng);
+            }
+        }
+
+        let newd = henyey_greenstein(d, g, rng);
+        let scattered = trace(Ray::new(p, newd, ray.time), scene, depth - 1, sampler, rng, None);
+
+        if let Some(aov) = aov {
+            aov.depth = Some(collision.t * ray.d.length());
+            aov.direct = collision.albedo * direct;
+            aov.indirect = collision.albedo * scattered;
+            aov.emission = (Colour::white() - collision.albedo) * collision.emission;
+        }
+        return (Colour::white() - collision.albedo) * collision.emission + collision.albedo * (direct + scattered);
+    }
+
+    match hit {
+        None => {
+            let sky = scene.background.radiance(d);
+            if let Some(aov) = aov {
+                aov.emission = sky;
+            }
+            return sky;
+        },
+        Some(hit) => {
+            let mat = hit.material;
+
+            // New direction, lit by the shading normal (normal and bump maps
+            // only change how the surface is lit, not where it is)
+            let newd = (mat.brdf)(hit.shading, d, sampler);
+
+            let emittance = mat.emission.as_ref().map_or(Colour::black(), |emission| emission.radiance(hit.uv, hit.point, hit.front_face));
+
+            let albedo = mat.albedo * if mat.cos {clamp(dot(d, -hit.shading))} else {1.};
+
+            // Offset the hit position a bit by the (geometric) normal, onto
+            // whichever side the new ray is heading; outside if it's reflected
+            // off the front or refracted out of the back, and inside otherwise
+            let bounced = Ray::new(hit.point + hit.normal * if dot(newd, hit.normal) > 0. {0.01} else {-0.01}, newd, ray.time);
+            let reflectance = hit.colour * trace(bounced, scene, depth - 1, sampler, rng, None) * albedo;
+
+            let direct = direct_light(scene, &hit, ray.time, sampler, rng);
+
+            let scale = 1./(PI * mat.prob);
+            if let Some(aov) = aov {
+                aov.depth = Some(hit.t * ray.d.length());
+                aov.normal = hit.shading;
+                aov.albedo = hit.colour * mat.albedo;
+                aov.object = Some(hit.id);
+                aov.material = Some(material_id(mat));
+                aov.direct = direct * scale;
+                aov.indirect = reflectance * scale;
+                aov.emission = emittance * scale;
+            }
+
+            return (emittance + reflectance + direct) * scale;
+        }
+    }
+
+}
+
+
+pub fn offset(hit: &SurfaceInteraction, d: Vec3, time: f32) -> Ray {
+    //// A ray leaving a surface, nudged off it onto whichever side it's going
+    Ray::new(hit.point + hit.normal * if dot(d, hit.normal) > 0. {0.01} else {-0.01}, d, time)
+}
+
+pub fn bounce(hit: &SurfaceInteraction, d: Vec3, sampler: &mut dyn Sampler) -> (Vec3, Colour) {
+    //// Off a surface by its material's own BRDF, as the path tracer does; the
+    //// new direction, and what the light along it gets multiplied by
+    let mat = hit.material;
+    let newd = (mat.brdf)(hit.shading, d, sampler).normalise();
+    let albedo = mat.albedo * if mat.cos {clamp(dot(d, -hit.shading))} else {1.};
+    (newd, hit.colour * (albedo / (PI * mat.prob)))
+}
+
+pub fn direct_light(scene: &Scene, hit: &SurfaceInteraction, time: f32, sampler: &mut dyn Sampler, rng: &mut Pcg) -> Colour {
+    //// Light straight from the (point, spot and directional) lights,
+    //// reflected off the diffuse part of a surface
+    let mat = hit.material;
+    if mat.diffuse <= 0. {
+        return Colour::black();
+    }
+
+    let mut direct = Colour::black();
+    let facing = if hit.front_face {hit.shading} else {-hit.shading};
+    for light in scene.lights.iter() {
+        if let Some(sample) = light.sample(hit.point, sampler.get_2d()) {
+            let cos = dot(sample.direction, facing);
+            if cos <= 0. {
+                continue;
+            }
+            let from = hit.point + hit.normal * if dot(sample.direction, hit.normal) > 0. {0.01} else {-0.01};
+            direct += sample.radiance * cos * shadow(scene, from, &sample, time, rng);
+        }
+    }
+    hit.colour * direct * (mat.albedo * mat.diffuse / PI)
+}
+
+pub fn shadow(scene: &Scene, from: Vec3, sample: &LightSample, time: f32, rng: &mut Pcg) -> f32 {
+    //// How much of a light's light gets to a point; none if something's
+    //// in the way, and only some if it has to go through a volume
+    let ray = Ray::new(from, sample.direction, time);
+    if scene.intersect(ray).is_some_and(|hit| hit.t < sample.distance) {
+        return 0.;
+    }
+    scene.volumes.iter().map(|volume| volume.transmittance(ray, sample.distance, rng)).product()
+}
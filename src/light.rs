@@ -0,0 +1,170 @@
+use super::vector::*;
+use super::colour::*;
+
+////////////////////////////////////////////////////////////
+//// Light that could reach a point from a light, if nothing
+//// gets in the way; a shadow ray needs checking for that
+////////////////////////////////////////////////////////////
+pub struct LightSample {
+    pub direction: Vec3, // Normalised, from the point towards the light
+    pub distance: f32, // How far along that the light is (infinite for directional lights)
+    pub radiance: Colour, // How much light arrives, square on
+}
+
+////////////////////////////////////////////////////////////////////////
+//// A light which rays can't hit (it's only a point, or a direction),
+//// so it has to be looked for from every point being lit. u is random,
+//// for lights that need a point picked on them
+////////////////////////////////////////////////////////////////////////
+pub trait Light: Send + Sync {
+    fn sample(&self, p: Vec3, u: (f32, f32)) -> Option<LightSample>;
+
+    // A ray of light leaving the light, for tracing light outwards from it.
+    // Lights which aren't anywhere in particular (directional ones) can't
+    fn emit(&self, _: (f32, f32)) -> Option<EmittedRay> {
+        None
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//// Light leaving a light in some direction; the power is how much
+//// it carries, over the pdf of having picked that direction
+/////////////////////////////////////////////////////////////////////
+pub struct EmittedRay {
+    pub origin: Vec3,
+    pub direction: Vec3, // Normalised
+    pub power: Colour,
+}
+
+//////////////////////////////////////////////////////////////////////
+//// A light shining equally in every direction from a point, getting
+//// dimmer with the square of the distance
+//////////////////////////////////////////////////////////////////////
+pub struct PointLight {
+    pub position: Vec3,
+    pub colour: Colour,
+    pub intensity: f32,
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: Vec3, _: (f32, f32)) -> Option<LightSample> {
+        let to = self.position - p;
+        let distance = to.length();
+
+        Some(LightSample {
+            direction: to / distance,
+            distance,
+            radiance: self.colour * (self.intensity / (distance * distance)),
+        })
+    }
+
+    fn emit(&self, (a, b): (f32, f32)) -> Option<EmittedRay> {
+        //// Evenly in every direction
+        let z = 1. - 2. * a;
+        let r = (1. - z * z).max(0.).sqrt();
+        let phi = 2. * PI * b;
+
+        Some(EmittedRay {
+            origin: self.position,
+            direction: Vec3::new(r * phi.cos(), r * phi.sin(), z),
+            power: self.colour * (self.intensity * 4. * PI),
+        })
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////
+//// A point light only shining in a cone; full brightness inside `inner`
+//// from the middle, fading out smoothly to nothing at `angle`
+/////////////////////////////////////////////////////////////////////////
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3, // Which way it points
+    pub colour: Colour,
+    pub intensity: f32,
+    pub angle: f32, // Half angles, in radians
+    pub inner: f32,
+}
+
+impl SpotLight {
+    fn falloff(&self, cos: f32) -> f32 {
+        //// How bright it is at some angle (the cos of it) from the middle; a
+        //// smoothstep across the edge of the cone
+        let (cos_outer, cos_inner) = (self.angle.cos(), self.inner.cos());
+        if cos <= cos_outer {
+            return 0.;
+        }
+        let t = clamp((cos - cos_outer) / (cos_inner - cos_outer).max(1e-6));
+        t * t * (3. - 2. * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: Vec3, _: (f32, f32)) -> Option<LightSample> {
+        let to = self.position - p;
+        let distance = to.length();
+        let direction = to / distance;
+
+        let falloff = self.falloff(dot(-direction, self.direction.normalise()));
+        if falloff <= 0. {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.colour * (self.intensity * falloff / (distance * distance)),
+        })
+    }
+
+    fn emit(&self, (a, b): (f32, f32)) -> Option<EmittedRay> {
+        //// Evenly over the cone
+        let cos_outer = self.angle.cos();
+        let cos = 1. - a * (1. - cos_outer);
+        let sin = (1. - cos * cos).max(0.).sqrt();
+        let phi = 2. * PI * b;
+        let middle = self.direction.normalise();
+        let (tangent, bitangent) = basis(middle);
+
+        Some(EmittedRay {
+            origin: self.position,
+            direction: (middle * cos + (tangent * phi.cos() + bitangent * phi.sin()) * sin).normalise(),
+            power: self.colour * (self.intensity * self.falloff(cos) * 2. * PI * (1. - cos_outer)),
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+//// Light coming from very far away (like the sun), all in one direction.
+//// With an angular diameter it comes from a small disc in the sky rather
+//// than a single direction, which gives shadows soft edges
+///////////////////////////////////////////////////////////////////////////
+pub struct DirectionalLight {
+    pub direction: Vec3, // Which way the light is going
+    pub colour: Colour,
+    pub irradiance: f32, // How much light falls on a surface square on
+    pub angular_diameter: f32, // In radians; the sun's is about 0.0093
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _: Vec3, (a, b): (f32, f32)) -> Option<LightSample> {
+        let towards = -self.direction.normalise();
+
+        let direction = if self.angular_diameter > 0. {
+            // Evenly over the disc of sky the light covers
+            let cos_max = (self.angular_diameter / 2.).cos();
+            let cos = 1. - a * (1. - cos_max);
+            let sin = (1. - cos * cos).max(0.).sqrt();
+            let azimuth = 2. * PI * b;
+            let (tangent, bitangent) = basis(towards);
+            (towards * cos + (tangent * azimuth.cos() + bitangent * azimuth.sin()) * sin).normalise()
+        } else {
+            towards
+        };
+
+        Some(LightSample {
+            direction,
+            distance: f32::INFINITY,
+            radiance: self.colour * self.irradiance,
+        })
+    }
+}
@@ -1,4 +1,6 @@
-use image::{ImageBuffer, Rgb, RgbImage, Pixel, GenericImageView, GenericImage};
+#![allow(clippy::needless_return, clippy::four_forward_slashes, clippy::upper_case_acronyms)]
+
+use std::sync::Arc;
 //use std::time::{SystemTime, UNIX_EPOCH};
 //use std::f32::INFINITY;
 
@@ -12,11 +14,215 @@ mod primitive;
 use primitive::*;
 mod texture;
 use texture::*;
+mod ray;
+mod scene;
+use scene::*;
+mod medium;
+use medium::*;
+mod noise;
 mod render;
 use render::*;
+mod csg;
+use csg::*;
+mod sdf;
+use sdf::*;
+mod fractal;
+use fractal::*;
+mod transform;
+use transform::*;
+mod sampler;
+use sampler::*;
+mod rng;
+mod film;
+use film::*;
+mod light;
+use light::*;
+mod sky;
+use sky::*;
+mod aov;
+use aov::*;
+mod denoise;
+use denoise::*;
+mod integrator;
+use integrator::*;
+mod bdpt;
+use bdpt::*;
+mod photon;
+use photon::*;
+mod debug;
+use debug::*;
 
 
 fn main() {    
+    const SAMPLES: u32 = 1024;
+
+    // Command line options
+    let mut sdf_file: Option<String> = None;
+    let mut bound = Bound::Sphere(2.);
+    let mut sampler_name = "sobol".to_string();
+    let mut seed: u32 = 4839;
+    let mut filter = Filter::Box {radius: 0.5};
+    let mut threshold: Option<f32> = None;
+    let mut min_samples = 16;
+    let mut heatmap: Option<String> = None;
+    let mut aovs: Vec<Aov> = vec![];
+    let mut denoiser: Option<Denoiser> = None;
+    let mut integrator_name = "path".to_string();
+    let mut volumes: Vec<String> = vec![];
+    let mut grid_file: Option<String> = None;
+    let mut lights = false;
+    let mut physical_sky = false;
+    let mut camera_name = "dof".to_string();
+    let mut f_number = 1.4;
+    let mut focal_length = 35.;
+    let mut focus: Option<f32> = None;
+    let mut texture_name: Option<String> = None;
+    let mut noise = Noise::Perlin;
+    let mut space = Space::Point;
+    let mut csg: Option<Operation> = None;
+    let mut material = LAMBERT;
+    let mut motion = false;
+    let mut normal_map: Option<String> = None;
+    let mut bump_map: Option<String> = None;
+    let mut bump_strength = 0.05;
+    let mut glow: Option<f32> = None;
+    let mut power: Option<f32> = None;
+    let mut photon_mapper = PhotonMapper::default();
+    let mut threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Replace the middle sphere with a marched shape described in a file
+            "--sdf" => sdf_file = args.next(),
+            // What the marched shape is known to fit in; a sphere or box, or nothing
+            "--bound" => bound = match args.next().as_deref() {
+                Some("sphere") => Bound::Sphere(2.),
+                Some("box") => Bound::Box(Vec3::new(2., 2., 2.)),
+                Some("none") => Bound::Unbounded,
+                other => panic!("Unknown bound {:?}", other),
+            },
+            // Where the random numbers for each sample come from
+            "--sampler" => sampler_name = args.next().expect("--sampler needs a name"),
+            // Everything random comes from this, so the same seed gives the same picture
+            "--seed" => seed = args.next().and_then(|s| s.parse().ok()).expect("--seed needs a number"),
+            // How samples are weighted into the pixels around them
+            "--filter" => filter = match args.next().as_deref() {
+                Some("box") => Filter::Box {radius: 0.5},
+                Some("tent") => Filter::Tent {radius: 1.},
+                Some("gaussian") => Filter::Gaussian {radius: 1.5, alpha: 2.},
+                Some("mitchell") => Filter::Mitchell {radius: 2., b: 1./3., c: 1./3.},
+                Some("lanczos") => Filter::Lanczos {radius: 3.},
+                other => panic!("Unknown filter {:?}", other),
+            },
+            // Sample until each pixel's relative error is below this, rather than SAMPLES
+            // times; SAMPLES is then the most a pixel can have
+            "--adaptive" => threshold = Some(args.next().and_then(|s| s.parse().ok()).expect("--adaptive needs a threshold")),
+            "--min-samples" => min_samples = args.next().and_then(|s| s.parse().ok()).expect("--min-samples needs a number"),
+            // Save a picture of how many samples each pixel took
+            "--heatmap" => heatmap = args.next(),
+            // Extra pictures to save alongside, as a list like depth,normal,albedo (or all)
+            "--aovs" => aovs = match args.next().expect("--aovs needs a list").as_str() {
+                "all" => ALL_AOVS.to_vec(),
+                list => list.split(',').map(|name| Aov::parse(name).unwrap()).collect(),
+            },
+            // Smooth out the noise once it's rendered
+            "--denoise" => denoiser = Some(Denoiser::default()),
+            // How light gets from the lights to the camera; bdpt and photon find caustics,
+            // but only handle surfaces (volumes are left out). There's also whitted, which
+            // is quick, and views for debugging scenes; ao, normals, depth, uv and cost
+            "--integrator" => integrator_name = args.next().expect("--integrator needs a name"),
+            // How many photons the photon mapper traces (each pass), and how far around
+            // a point it gathers them from; caustics are gathered from closer by
+            "--photons" => photon_mapper.photons = args.next().and_then(|s| s.parse().ok()).expect("--photons needs a number"),
+            "--photon-radius" => photon_mapper.radius = args.next().and_then(|s| s.parse().ok()).expect("--photon-radius needs a number"),
+            "--caustic-radius" => photon_mapper.caustic_radius = args.next().and_then(|s| s.parse().ok()).expect("--caustic-radius needs a number"),
+            // Trace the photons this many times over, gathering from less far each time
+            "--passes" => photon_mapper.passes = args.next().and_then(|s| s.parse().ok()).expect("--passes needs a number"),
+            // Fog and smoke to add to the scene, as a list like haze,cloud; smoke fills
+            // the glass orb, haze is over everything and cloud is over the back
+            "--volumes" => volumes = args.next().expect("--volumes needs a list").split(',').map(String::from).collect(),
+            // Take the cloud's shape from a grid of densities in a file, rather than noise
+            "--grid" => grid_file = args.next(),
+            // Light the scene with a lamp, a spotlight and the sun, as well as the sky
+            "--lights" => lights = true,
+            // A clear afternoon sky (with its sun) rather than the plain gradient
+            "--sky" => physical_sky = true,
+            // What takes the picture; dof (with depth of field) or pinhole, ortho with no
+            // perspective, fisheye or equisolid lenses seeing 180 degrees, equirect or
+            // cubemap panoramas seeing all the way round (which set their own widths),
+            // and physical, a full frame camera with the picture the shape of its sensor
+            "--camera" => camera_name = args.next().expect("--camera needs a name"),
+            // The physical camera's lens (focal length in mm), and how far away (in metres)
+            // it's focused; without --focus, it focuses on whatever's in the middle
+            "--f-stop" => f_number = args.next().and_then(|s| s.parse().ok()).expect("--f-stop needs a number"),
+            "--focal-length" => focal_length = args.next().and_then(|s| s.parse().ok()).expect("--focal-length needs a number"),
+            "--focus" => focus = Some(args.next().and_then(|s| s.parse().ok()).expect("--focus needs a number")),
+            // Give the middle sphere (or marched shape) a texture; check, or marble, noise, wood or
+            // granite, made of perlin or simplex noise, through space or laid out over its uv
+            "--texture" => texture_name = args.next(),
+            "--noise" => noise = match args.next().as_deref() {
+                Some("perlin") => Noise::Perlin,
+                Some("simplex") => Noise::Simplex,
+                other => panic!("Unknown noise {:?}", other),
+            },
+            "--uv" => space = Space::Uv,
+            // And what it's made of
+            "--material" => material = match args.next().as_deref() {
+                Some("lambert") => LAMBERT,
+                Some("mirror") => MIRROR,
+                Some("metal") => METAL,
+                Some("glass") => GLASS,
+                Some("light") => LIGHT,
+                other => panic!("Unknown material {:?}", other),
+            },
+            // Combine the glass orb with a rod running through it from front to back
+            "--csg" => csg = Some(match args.next().as_deref() {
+                Some("union") => Operation::Union,
+                Some("intersection") => Operation::Intersection,
+                Some("difference") => Operation::Difference,
+                other => panic!("Unknown operation {:?}", other),
+            }),
+            // Add a ball rolling past and a cube spinning while the shutter's open
+            "--motion" => motion = true,
+            // Tile the floor with a normal map, or a height map (and how high it goes)
+            // read from an image; it repeats every metre
+            "--normal-map" => normal_map = args.next(),
+            "--bump-map" => bump_map = args.next(),
+            "--bump-strength" => bump_strength = args.next().and_then(|s| s.parse().ok()).expect("--bump-strength needs a number"),
+            // Make the small sphere glow the colour of something this hot (in kelvin),
+            // and give off this many watts rather than being as bright as white
+            "--glow" => glow = Some(args.next().and_then(|s| s.parse().ok()).expect("--glow needs a number")),
+            "--power" => power = Some(args.next().and_then(|s| s.parse().ok()).expect("--power needs a number")),
+            "--threads" => threads = args.next().and_then(|s| s.parse().ok()).expect("--threads needs a number"),
+            _ => panic!("Unknown argument {}", arg),
+        }
+    }
+
+    let sampler: Box<dyn Sampler> = match sampler_name.as_str() {
+        "independent" => Box::new(Independent::new(seed)),
+        "stratified" => Box::new(Stratified::new(SAMPLES, seed)),
+        "halton" => Box::new(Halton::new(seed)),
+        "sobol" => Box::new(Sobol::new(seed)),
+        other => panic!("Unknown sampler {}", other),
+    };
+
+    let integrator: Box<dyn Integrator> = match integrator_name.as_str() {
+        "path" => Box::new(PathTracer::default()),
+        "bdpt" => Box::new(Bdpt::default()),
+        "photon" => {
+            photon_mapper.seed = seed;
+            Box::new(photon_mapper)
+        },
+        "whitted" => Box::new(Whitted::default()),
+        "ao" => Box::new(AmbientOcclusion::default()),
+        "normals" => Box::new(ShowNormals),
+        "depth" => Box::new(ShowDepth::default()),
+        "uv" => Box::new(ShowUv),
+        "cost" => Box::new(ShowCost::default()),
+        other => panic!("Unknown integrator {}", other),
+    };
+
     // Setting up the final image
     let width: u32;
     let height: u32;
@@ -29,7 +235,6 @@ fn main() {
         height = 320;
     }
 
-    const SAMPLES: u32 = 1024;
 
 
     // Setting up the camera
@@ -45,119 +250,395 @@ fn main() {
     let floor = Plane {
         point: Vec3::new(0., 0., 0.),
         normal: Vec3::new(0., 1., 0.),
-        colour: Colour::new(0.2, 0.4, 0.2),
-        material: LAMBERT,
+        texture: Arc::new(Colour::new(0.2, 0.4, 0.2)),
+        material: Material {
+            normal_map: normal_map.map(|file| Arc::new(TextImage::load(&file, false).unwrap()) as Arc<dyn Texture>),
+            bump: bump_map.map(|file| Bump {
+                height: Arc::new(TextImage::load(&file, false).unwrap()),
+                strength: bump_strength,
+            }),
+            ..LAMBERT
+        },
     };
 
 
     let orb = Sphere {
         centre: Vec3::new(1.8, 1., 0.),
         radius: 1.,
-        texture: |_| {Colour::new(1.0, 0.8, 0.8)},
+        texture: Arc::new(Colour::new(1.0, 0.8, 0.8)),
         material: GLASS,
     };
 
 
+    let texture: Arc<dyn Texture> = match texture_name.as_deref() {
+        None => Arc::new(Colour::new(0.2, 0.2, 0.2)),
+        Some("check") => Arc::new(TextCheck {
+            col1: Colour::new(0.9, 0.9, 0.9),
+            col2: Colour::new(0.1, 0.1, 0.1),
+        }),
+        Some("marble") => Arc::new(TextMarble {
+            noise,
+            space,
+            scale: 0.2,
+            turbulence: 2.,
+            octaves: 6,
+            base: Colour::new(0.9, 0.9, 0.85),
+            vein: Colour::new(0.3, 0.3, 0.35),
+        }),
+        Some("noise") => Arc::new(TextNoise {
+            noise,
+            space,
+            scale: 0.3,
+            octaves: 5,
+            turbulent: false,
+            col1: Colour::new(0.1, 0.2, 0.5),
+            col2: Colour::new(0.9, 0.8, 0.6),
+        }),
+        Some("wood") => Arc::new(TextWood {
+            noise,
+            space,
+            scale: 0.1,
+            wobble: 0.3,
+            light: Colour::new(0.75, 0.55, 0.35),
+            dark: Colour::new(0.4, 0.25, 0.12),
+        }),
+        Some("granite") => Arc::new(TextGranite {
+            noise,
+            space,
+            scale: 0.05,
+            base: Colour::new(0.35, 0.33, 0.33),
+            grain: Colour::new(0.8, 0.75, 0.72),
+            crystal: Colour::new(0.05, 0.05, 0.05),
+        }),
+        Some(other) => panic!("Unknown texture {}", other),
+    };
+
     let check = Sphere {
         centre: Vec3::new(0., 0.7, 0.),
         radius: 0.7,
-        //texture: |v| { text_check(v) },
-        texture: |_| { Colour::new(0.2, 0.2, 0.2) },
-        material: LAMBERT,
+        texture: texture.clone(),
+        material: material.clone(),
     };
 
-    fn sphere_dist(p: Vec3) -> f32 {
-        p.length() - 0.7
-    }
-
-    const ITERATIONS: i32 = 10;
-    const BAILOUT: f32 = 10.;
-    const POWER: f32 = 2.;
-
-    fn mand_dist(pos: Vec3) -> f32 {
-        let mut z = pos;
-        let mut dr = 1.;
-        let mut r: f32 = 0.;
-
-        for _ in 0..ITERATIONS {
-            r = z.length();
-            if r > BAILOUT {break;}
-
-            let mut theta = (z.z()/ r).acos();
-            let mut phi = (z.y()).atan2(z.x());
-            dr = r.powf(POWER - 1.) * POWER * dr + 1.;
-
-            let zr = r.powf(POWER);
-            theta = theta * POWER;
-            phi = phi * POWER;
-
-            z = zr * Vec3::new(theta.sin()*phi.cos(), phi.sin() * theta.sin(), theta.cos());
-            z += pos;
-        }
-
-        0.5 * r.ln() * r/dr
-    }
-
-    let check2 = March {
+    let _check2 = March {
         centre: Vec3::new(0., 0.7, 0.),
         scale: 0.7,
-        dist: mand_dist,
-        colour: Colour::white(),
+        dist: Sdf::Fractal(Fractal {
+            iterations: 10,
+            ..Fractal::new(Estimator::Mandelbulb {power: 2., bailout: 10.})
+        }),
+        bound: Bound::Sphere(1.2),
+        settings: MarchSettings::default(),
+        texture: Arc::new(Colour::white()),
         material: LAMBERT,
     };
 
     let light = Sphere {
         centre: Vec3::new(-1.3, 0.5, 0.),
         radius: 0.5,
-        texture: |_| { Colour::new(1., 0.8, 0.) },
-        material: MIRROR, //LIGHT
+        texture: Arc::new(Colour::new(1., 0.8, 0.)),
+        material: match glow {
+            None => MIRROR,
+            Some(temperature) => Material {
+                emission: Some(match power {
+                    None => Emission::kelvin(temperature, 1.),
+                    Some(watts) => Emission::watts(blackbody(temperature), watts, 4. * PI * 0.5 * 0.5, false),
+                }),
+                ..LIGHT
+            },
+        },
     };
 
     let cube = AABB {
         min: Vec3::new(-1., -1., -1.),
         max: Vec3::new(1., 1., 1.),
         material: LAMBERT,
-        colour: Colour::new(0.8, 0.1, 0.1),
+        texture: Arc::new(Colour::new(0.8, 0.1, 0.1)),
     };
 
-    let trig = Triangle {
+    let _trig = Triangle {
         v0: Vec3::new(-1., -1., 0.),
         v1: Vec3::new(0., 1., 0.),
         v2: Vec3::new(1., -1., 0.),
         material: LAMBERT,
-        colour: Colour::white(),
+        texture: Arc::new(Colour::white()),
     };
 
-    let rect = Rect_XY {
+    let _rect = Rect_XY {
         x0: 3., x1: 5.,
         y0: 1., y1: 3.,
         k: -2.,
         material: LAMBERT,
-        colour: Colour::white(),
+        texture: Arc::new(Colour::white()),
+    };
+
+    // The orb with a rod added, kept or drilled out
+    let orb: Box<dyn Object> = match csg {
+        None => Box::new(orb),
+        Some(operation) => Box::new(CSG {
+            left: Box::new(orb),
+            right: Box::new(Cylinder {
+                base: Vec3::new(1.8, 1., -1.5),
+                axis: Vec3::new(0., 0., 1.),
+                radius: 0.4,
+                height: 3.,
+                texture: Arc::new(Colour::new(1.0, 0.8, 0.8)),
+                material: GLASS,
+            }),
+            operation,
+        }),
+    };
+
+    // A ball rolling past while the shutter is open
+    let rolling = MovingSphere {
+        centre0: Vec3::new(-1., 0.3, -1.),
+        centre1: Vec3::new(-0.5, 0.3, -1.),
+        time0: 0.,
+        time1: 1.,
+        radius: 0.3,
+        texture: Arc::new(Colour::new(0.2, 0.3, 0.8)),
+        material: LAMBERT,
+    };
+
+    // The cube, shrunk and spinning a quarter turn
+    let spinning = Instance {
+        object: Box::new(cube),
+        start: Transform {
+            translation: Vec3::new(1., 0.3, -1.5),
+            scale: 0.3,
+            ..Transform::identity()
+        },
+        end: Transform {
+            translation: Vec3::new(1., 0.3, -1.5),
+            scale: 0.3,
+            angle: PI/2.,
+            ..Transform::identity()
+        },
+        time0: 0.,
+        time1: 1.,
     };
 
     //let spheres = [small, big, other, left];
-    let shapes: Vec<Box<dyn Object>> = vec![Box::new(floor), Box::new(orb), Box::new(check), Box::new(light)];
+    let mut shapes: Vec<Box<dyn Object>> = vec![Box::new(floor), orb, Box::new(light)];
+
+    match sdf_file {
+        Some(file) => shapes.push(Box::new(March {
+            centre: Vec3::new(0., 0.7, 0.),
+            scale: 1.,
+            dist: Sdf::load(&file).unwrap(),
+            bound,
+            settings: MarchSettings {relaxation: 1.5, ..MarchSettings::default()},
+            texture,
+            material,
+        })),
+        None => shapes.push(Box::new(check)),
+    }
+
+    if motion {
+        shapes.push(Box::new(rolling));
+        shapes.push(Box::new(spinning));
+    }
+
+    let mut scene = Scene::new(shapes);
+
+    // Smoke filling the glass orb
+    let smoke = Volume {
+        boundary: Some(Box::new(Sphere {
+            centre: Vec3::new(1.8, 1., 0.),
+            radius: 0.98,
+            texture: Arc::new(Colour::white()),
+            material: GLASS,
+        })),
+        medium: Box::new(Homogeneous {
+            density: 2.,
+            albedo: Colour::new(0.8, 0.8, 0.8),
+            anisotropy: 0.3,
+        }),
+    };
+
+    // A thin haze over everything
+    let haze = Volume {
+        boundary: None,
+        medium: Box::new(Homogeneous {
+            density: 0.01,
+            albedo: Colour::white(),
+            anisotropy: 0.7,
+        }),
+    };
+
+    // A cloud over the back of the scene
+    let density: Box<dyn Density> = match grid_file {
+        Some(file) => Box::new(Grid::load(&file).unwrap()),
+        None => Box::new(NoiseDensity {
+            frequency: 4.,
+            octaves: 5,
+            coverage: 0.4,
+        }),
+    };
+
+    let cloud = Volume::grid(Heterogeneous {
+        min: Vec3::new(-3., 2.5, 2.),
+        max: Vec3::new(3., 4., 5.),
+        density,
+        scale: 6.,
+        albedo: Colour::new(0.95, 0.95, 0.95),
+        emission: Colour::black(),
+        anisotropy: 0.5,
+    });
+
+    let (mut smoke, mut haze, mut cloud) = (Some(smoke), Some(haze), Some(cloud));
+    for name in volumes.iter() {
+        let volume = match name.as_str() {
+            "smoke" => smoke.take(),
+            "haze" => haze.take(),
+            "cloud" => cloud.take(),
+            other => panic!("Unknown volume {}", other),
+        };
+        scene.volumes.extend(volume);
+    }
+
+    // A warm lamp above and to the left, a spotlight on the middle
+    // sphere, and low evening sun
+    let lamp = PointLight {
+        position: Vec3::new(-2., 3., 1.),
+        colour: Colour::new(1., 0.85, 0.6),
+        intensity: 8.,
+    };
+
+    let spot = SpotLight {
+        position: Vec3::new(0., 4., -1.),
+        direction: Vec3::new(0., -3.3, 1.),
+        colour: Colour::white(),
+        intensity: 20.,
+        angle: 0.3,
+        inner: 0.2,
+    };
+
+    let sun = DirectionalLight {
+        direction: Vec3::new(1., -0.4, 1.),
+        colour: Colour::new(1., 0.9, 0.75),
+        irradiance: 2.,
+        angular_diameter: 0.0093,
+    };
+
+    if lights {
+        scene.lights.push(Box::new(lamp));
+        scene.lights.push(Box::new(spot));
+        scene.lights.push(Box::new(sun));
+    }
+
+    // Clear afternoon sky, with the sun where the old one was
+    let sky = PhysicalSky::new(0.615, -2.356, 3., Colour::new(0.3, 0.3, 0.3), 0.05);
+
+    if physical_sky {
+        scene.lights.push(Box::new(sky.sun()));
+        scene.background = Box::new(sky);
+    }
 
     //let mut seed: u32 = SystemTime::now().duration_since(UNIX_EPOCH).ok().unwrap().as_millis() as u32;
 
-    /*let cam = SimpleCamera {
-        fov: PI/4., //0.6435
-        position: eye,
-        looking: looking,
-        global_up: global_up,
-    };*/
-
-    let cam = DOFCamera {
-        fov: PI/4., //0.6435
-        position: eye,
-        looking: looking,
-        global_up: global_up,
-        aperture: 0.2,
-        //focus: 3.42,
-        focus: (Vec3::new(0., 0.7, 0.)  + Vec3::new(0., 0.7, 0.) - eye).length(),
-    };
-
-    println!("{}", cam.focus);
-    cam.render(shapes, width, height, SAMPLES, "new_result.png".to_string());
+    // Panoramas are twice as wide as they are high, and cubemaps three faces by two;
+    // the physical camera makes it the shape of its sensor
+    let mut width = match camera_name.as_str() {
+        "equirect" => height * 2,
+        "cubemap" => height * 3 / 2,
+        _ => width,
+    };
+
+    let cam: Box<dyn Camera> = match camera_name.as_str() {
+        "dof" => {
+            let cam = DOFCamera {
+                fov: PI/4., //0.6435
+                position: eye,
+                looking,
+                global_up,
+                shutter_open: 0.,
+                shutter_close: 1.,
+                aperture: 0.2,
+                //focus: 3.42,
+                focus: (Vec3::new(0., 0.7, 0.)  + Vec3::new(0., 0.7, 0.) - eye).length(),
+            };
+            println!("{}", cam.focus);
+            Box::new(cam)
+        },
+        "pinhole" => Box::new(SimpleCamera {
+            fov: PI/4.,
+            position: eye,
+            looking,
+            global_up,
+            shutter_open: 0.,
+            shutter_close: 1.,
+        }),
+        "ortho" => Box::new(OrthoCamera {
+            size: 4.,
+            position: eye,
+            looking,
+            global_up,
+            shutter_open: 0.,
+            shutter_close: 1.,
+        }),
+        "fisheye" | "equisolid" => Box::new(FisheyeCamera {
+            fov: PI,
+            projection: if camera_name == "fisheye" {Fisheye::Equidistant} else {Fisheye::Equisolid},
+            position: eye,
+            looking,
+            global_up,
+            shutter_open: 0.,
+            shutter_close: 1.,
+        }),
+        "equirect" => Box::new(EquirectCamera {
+            position: eye,
+            looking,
+            global_up,
+            shutter_open: 0.,
+            shutter_close: 1.,
+        }),
+        "cubemap" => Box::new(CubemapCamera {
+            position: eye,
+            looking,
+            global_up,
+            shutter_open: 0.,
+            shutter_close: 1.,
+        }),
+        "physical" => {
+            let mut cam = PhysicalCamera {
+                focal_length,
+                sensor: (36., 24.),
+                f_number,
+                focus: focus.unwrap_or(1.),
+                blades: 6,
+                position: eye,
+                looking,
+                global_up,
+                shutter_open: 0.,
+                shutter_close: 1.,
+            };
+            if focus.is_none() {
+                cam.autofocus(&scene);
+            }
+            width = cam.resolution(height).0;
+            Box::new(cam)
+        },
+        other => panic!("Unknown camera {}", other),
+    };
+
+    let mut settings = Settings {
+        width,
+        height,
+        samples: SAMPLES,
+        sampler,
+        filter,
+        threads,
+        filename: "new_result.png".to_string(),
+        adaptive: threshold.map(|threshold| Adaptive {
+            min_samples: min_samples.min(SAMPLES),
+            max_samples: SAMPLES,
+            threshold,
+        }),
+        heatmap,
+        aovs,
+        denoiser,
+        integrator,
+    };
+    cam.render(scene, &mut settings);
 }
@@ -1,26 +1,180 @@
 use super::vector::*;
+use super::texture::*;
+use super::sampler::*;
+use super::colour::*;
+use std::sync::Arc;
 
-pub fn random_float(seed: &mut u32) -> f32 {
-    // Random float in [0, 1)
-    let mut x = *seed;
-    x ^= x >> 13;
-    x ^= x << 17;
-    x ^= x >> 5;
-    *seed = x;
-    let float_bits = (x & 0x007FFFFF) | 0x3F800000;
-    let float: f32 = unsafe { ::core::mem::transmute(float_bits) };
-    return float - 1.0;
-}
-
-
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Material {
     pub albedo: f32,
-    pub brdf: fn(Vec3, Vec3, &mut u32) -> Vec3,
+    pub brdf: fn(Vec3, Vec3, &mut dyn Sampler) -> Vec3,
     pub prob: f32,
     pub cos: bool, // Is albedo via cos required (n.l term or something)
     // Maybe I'll put colour in here, maybe not. Not sure what else is needed
-    pub emission: f32,
+    pub emission: Option<Emission>,
+    // How much of the reflected light is reflected diffusely; lights
+    // that rays can't hit (like point lights) only light this part
+    pub diffuse: f32,
+    // Tangent space normals, stored as colours like most normal maps are
+    // (x along u, y along v, z out of the surface; each from 0 to 1)
+    pub normal_map: Option<Arc<dyn Texture>>,
+    pub bump: Option<Bump>,
+}
+
+//////////////////////////////////////////////////////////////
+//// A height map which makes a surface look bumpy. Heights
+//// are the brightness of the texture, times the strength
+//////////////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct Bump {
+    pub height: Arc<dyn Texture>,
+    pub strength: f32,
+}
+
+impl Bump {
+    fn height(&self, uv: (f32, f32), p: Vec3) -> f32 {
+        let col = self.height.value(uv, p);
+        self.strength * (col.x() + col.y() + col.z()) / 3.
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+//// Light given off by a surface; the radiance is the colour (scaled so
+//// it's as bright as white would be) times the strength, times the
+//// texture if there is one. One-sided emitters only glow outwards
+////////////////////////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct Emission {
+    pub colour: Colour,
+    pub strength: f32,
+    pub texture: Option<Arc<dyn Texture>>,
+    pub two_sided: bool,
+}
+
+impl Emission {
+    pub fn rgb(colour: Colour, strength: f32) -> Emission {
+        Emission {colour, strength, texture: None, two_sided: true}
+    }
+
+    pub fn kelvin(temperature: f32, strength: f32) -> Emission {
+        //// The colour something that hot glows
+        Emission::rgb(blackbody(temperature), strength)
+    }
+
+    pub fn watts(colour: Colour, power: f32, area: f32, two_sided: bool) -> Emission {
+        //// Emission giving off `power` in total from a surface of some area; it
+        //// glows the same in every direction, so the radiance is power over pi
+        //// (the integral of cos over the hemisphere) per unit area, per side
+        let sides = if two_sided {2.} else {1.};
+        Emission {colour, strength: power / (PI * area * sides), texture: None, two_sided}
+    }
+
+    pub fn radiance(&self, uv: (f32, f32), p: Vec3, front_face: bool) -> Colour {
+        if !front_face && !self.two_sided {
+            return Colour::black();
+        }
+        let colour = match &self.texture {
+            Some(texture) => self.colour * texture.value(uv, p),
+            None => self.colour,
+        };
+        colour * self.strength
+    }
+}
+
+fn lobe(x: f32, mean: f32, below: f32, above: f32) -> f32 {
+    //// A Gaussian with different widths either side of the mean
+    let t = (x - mean) / if x < mean {below} else {above};
+    (-0.5 * t * t).exp()
+}
+
+pub fn blackbody(temperature: f32) -> Colour {
+    //// The (linear) colour of a black body at some temperature in Kelvin,
+    //// as bright as white. Planck's law, integrated against the CIE colour
+    //// matching functions (Wyman, Sloan and Shirley's fit), into sRGB
+    const C2: f32 = 1.4388e-2; // hc/k, in metre Kelvin
+    let (mut x, mut y, mut z) = (0., 0., 0.);
+
+    for step in 0..=80 {
+        let wavelength = 380. + 5. * step as f32; // nm
+        let metres = wavelength * 1e-9;
+        // Scaled so the numbers stay in range
+        let radiance = 1. / ((metres * 1e6).powi(5) * ((C2 / (metres * temperature)).exp() - 1.));
+
+        x += radiance * (1.056 * lobe(wavelength, 599.8, 37.9, 31.0) + 0.362 * lobe(wavelength, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(wavelength, 501.1, 20.4, 26.2));
+        y += radiance * (0.821 * lobe(wavelength, 568.8, 46.9, 40.5) + 0.286 * lobe(wavelength, 530.9, 16.3, 31.1));
+        z += radiance * (1.217 * lobe(wavelength, 437.0, 11.8, 36.0) + 0.681 * lobe(wavelength, 459.0, 26.0, 13.8));
+    }
+
+    let rgb = Colour::new(
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ).max(0.);
+
+    // Too cold to glow visibly at all
+    if rgb.luminance() <= 0. {
+        return Colour::black();
+    }
+    rgb / rgb.luminance()
+}
+
+impl Material {
+    pub fn shading_normal(&self, p: Vec3, n: Vec3, uv: (f32, f32), dpdu: Vec3, dpdv: Vec3) -> Vec3 {
+        //// The normal to light the surface with, once the
+        //// normal map and bump map have been taken into account
+        let n = n.normalise();
+
+        // A tangent frame lined up with the uvs where possible
+        let tangent = dpdu - n * dot(n, dpdu);
+        let (tangent, bitangent) = if tangent.length_squared() > 0. {
+            let tangent = tangent.normalise();
+            let bitangent = cross(n, tangent);
+            // uv may be mirrored
+            (tangent, if dot(bitangent, dpdv) < 0. {-bitangent} else {bitangent})
+        } else {
+            basis(n)
+        };
+
+        let mut shading = n;
+
+        if let Some(bump) = &self.bump {
+            // Move the surface along the normal by the height, and see
+            // which way it now faces by nudging along u and v
+            const DELTA: f32 = 0.001;
+            let height = bump.height(uv, p);
+            let du = (bump.height((uv.0 + DELTA, uv.1), p + dpdu * DELTA) - height) / DELTA;
+            let dv = (bump.height((uv.0, uv.1 + DELTA), p + dpdv * DELTA) - height) / DELTA;
+
+            let bumped_u = dpdu + du * n;
+            let bumped_v = dpdv + dv * n;
+            let bumped = cross(bumped_u, bumped_v);
+
+            if bumped.length_squared() > 0. {
+                shading = bumped.normalise();
+                if dot(shading, n) < 0. {
+                    shading = -shading;
+                }
+            }
+        }
+
+        if let Some(normal_map) = &self.normal_map {
+            let col = normal_map.value(uv, p);
+            let local = 2. * col - Vec3::new(1., 1., 1.);
+
+            // Tangent frame around the (possibly bumped) normal
+            let t = (tangent - shading * dot(shading, tangent)).normalise();
+            let b = cross(shading, t);
+            let b = if dot(b, bitangent) < 0. {-b} else {b};
+
+            let mapped = t * local.x() + b * local.y() + shading * local.z();
+            if mapped.length_squared() > 0. {
+                shading = mapped.normalise();
+            }
+        }
+
+        shading
+    }
 }
 
 
@@ -50,19 +204,19 @@ fn schlick(cos: f32, ratio: f32) -> f32 {
 }
 
 
-fn brdf_mirror(n: Vec3, d: Vec3, _: &mut u32) -> Vec3 {
+fn brdf_mirror(n: Vec3, d: Vec3, _: &mut dyn Sampler) -> Vec3 {
     // BRDF for a mirror (every ray is reflected)
     d - 2.*dot(n, d)*n
 }
 
-fn brdf_glass(n: Vec3, d: Vec3, seed: &mut u32) -> Vec3 {
+fn brdf_glass(n: Vec3, d: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
     // BRDF for glass; some rays reflected, others refracted
     let cos = dot(d, -n); // Cosine of angle of incoming
     let refr = 0.7; // Refractive index
     
     // ... dot(n, d) < 0 ...
 
-    let rand = random_float(seed);
+    let rand = sampler.get_1d();
 
     // && random_float(seed) > schlick(cos, refr) 
     if cos > 0. {
@@ -86,33 +240,30 @@ fn brdf_glass(n: Vec3, d: Vec3, seed: &mut u32) -> Vec3 {
     }
 }
 
-fn brdf_metal(n: Vec3, d: Vec3, seed: &mut u32) -> Vec3 {
+fn brdf_metal(n: Vec3, d: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
     // Metal sort of thing
-    return(d-2.*dot(n, d)*n + 0.4 * brdf_lambert(n, d, seed)).normalise()
+    return(d-2.*dot(n, d)*n + 0.4 * brdf_lambert(n, d, sampler)).normalise()
 }
 
-fn brdf_lambert(n: Vec3, _: Vec3, seed: &mut u32) -> Vec3 {
+fn brdf_lambert(n: Vec3, _: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
     // Return a cosine weighted ray, centered around
     // the normal n
     // Ignore the direction of the incoming ray
-    let azmith = random_float(seed) * PI * 2.;
-    let y = random_float(seed);
+    let (a, y) = sampler.get_2d();
+    let azmith = a * PI * 2.;
     let sin_elevation = (1. - y*y).sqrt();
     let rand = Vec3::new(sin_elevation * azmith.cos(), y, sin_elevation * azmith.sin());
 
-    let nt: Vec3;
-    let nb: Vec3;
-
-    if n.x().abs() > n.y().abs() {
+    let nt = if n.x().abs() > n.y().abs() {
         //nt = vec3(n.z, 0., -n.x).normalize();
-        nt = Vec3::new(n.z(), 0., -n.x()).normalise();
+        Vec3::new(n.z(), 0., -n.x()).normalise()
     } else {
         //nt = vec3(0., -n.z, n.y).normalize();
-        nt = Vec3::new(0., -n.z(), n.y()).normalise();
-    }
+        Vec3::new(0., -n.z(), n.y()).normalise()
+    };
 
     //nb = n.cross(nt);
-    nb = cross(n, nt);
+    let nb = cross(n, nt);
 
     Vec3::new(
         rand.x() * nb.x() + rand.y() * n.x() + rand.z() * nt.x(),
@@ -126,7 +277,10 @@ pub const LAMBERT: Material = Material {
     brdf: brdf_lambert,
     prob: 1./PI, 
     cos: false,
-    emission: 0.,
+    emission: None,
+    diffuse: 1.,
+    normal_map: None,
+    bump: None,
 };
 
 pub const MIRROR: Material = Material {
@@ -134,7 +288,10 @@ pub const MIRROR: Material = Material {
     brdf: brdf_mirror,
     prob: 1./PI,
     cos: false,
-    emission: 0.,
+    emission: None,
+    diffuse: 0.,
+    normal_map: None,
+    bump: None,
 };
 
 pub const LIGHT: Material = Material {
@@ -142,7 +299,15 @@ pub const LIGHT: Material = Material {
     brdf: brdf_lambert,
     prob: 1./PI,
     cos: false,
-    emission: 0.99,
+    emission: Some(Emission {
+        colour: Colour::new(1., 1., 1.),
+        strength: 0.99,
+        texture: None,
+        two_sided: true,
+    }),
+    diffuse: 1.,
+    normal_map: None,
+    bump: None,
 };
 
 pub const GLASS: Material = Material {
@@ -150,7 +315,10 @@ pub const GLASS: Material = Material {
     brdf: brdf_glass,
     prob: 1./PI,
     cos: false,
-    emission: 0.,
+    emission: None,
+    diffuse: 0.,
+    normal_map: None,
+    bump: None,
 };
 
 pub const METAL: Material = Material {
@@ -158,5 +326,8 @@ pub const METAL: Material = Material {
     brdf: brdf_metal,
     prob: 1./PI,
     cos: true,
-    emission: 0.,
-};
\ No newline at end of file
+    emission: None,
+    diffuse: 0.,
+    normal_map: None,
+    bump: None,
+};
@@ -0,0 +1,441 @@
+use super::vector::*;
+use super::colour::*;
+use super::ray::*;
+use super::primitive::*;
+use super::material::*;
+use super::noise::*;
+use super::rng::*;
+use std::sync::Arc;
+
+/////////////////////////////////////////////////////////////
+//// Where a ray ran into something in a medium, and what's
+//// there; the light given off, and how much of the rest of
+//// the light is scattered rather than absorbed
+/////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct Collision {
+    pub t: f32,
+    pub albedo: Colour,
+    pub emission: Colour,
+}
+
+////////////////////////////////////////////////////////////////////
+//// Something light travels through which can scatter it (fog, smoke)
+////////////////////////////////////////////////////////////////////
+pub trait Medium: Send + Sync {
+    // Where a ray going from t_min to t_max through the medium first
+    // runs into something, if it does
+    fn sample(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut Pcg) -> Option<Collision>;
+    // How much light gets from t_min to t_max without hitting anything
+    fn transmittance(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut Pcg) -> f32;
+    // Henyey-Greenstein asymmetry; 0 scatters evenly, towards 1 mostly forwards
+    fn anisotropy(&self) -> f32;
+}
+
+//////////////////////////////////////////////
+//// The same everywhere; plain fog or smoke
+//////////////////////////////////////////////
+pub struct Homogeneous {
+    pub density: f32, // Chance of hitting something per unit travelled
+    pub albedo: Colour, // How much of what's hit gets scattered rather than absorbed
+    pub anisotropy: f32,
+}
+
+impl Medium for Homogeneous {
+    fn sample(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut Pcg) -> Option<Collision> {
+        // Distance to the next particle is exponentially distributed
+        let distance = -(1. - rng.next_float()).ln() / self.density;
+        let t = t_min + distance / ray.d.length();
+
+        if t < t_max {
+            Some(Collision {t, albedo: self.albedo, emission: Colour::black()})
+        } else {
+            None
+        }
+    }
+
+    fn transmittance(&self, ray: Ray, t_min: f32, t_max: f32, _: &mut Pcg) -> f32 {
+        (-self.density * (t_max - t_min) * ray.d.length()).exp()
+    }
+
+    fn anisotropy(&self) -> f32 {
+        self.anisotropy
+    }
+}
+
+///////////////////////////////////////////////////////////////
+//// A medium filling the inside of a solid, or (with no
+//// boundary) the whole scene
+///////////////////////////////////////////////////////////////
+pub struct Volume {
+    pub boundary: Option<Box<dyn Solid>>,
+    pub medium: Box<dyn Medium>,
+}
+
+impl Volume {
+    pub fn sample(&self, ray: Ray, t_max: f32, rng: &mut Pcg) -> Option<Collision> {
+        //// Where the ray scatters inside the volume before reaching t_max
+        let boundary = match &self.boundary {
+            None => return self.medium.sample(ray, 0., t_max, rng),
+            Some(boundary) => boundary,
+        };
+
+        for Span(entry, exit) in boundary.intervals(ray) {
+            let (start, end) = (entry.t.max(0.), exit.t.min(t_max));
+            if start >= end {
+                continue;
+            }
+            if let Some(scatter) = self.medium.sample(ray, start, end, rng) {
+                return Some(scatter);
+            }
+        }
+        None
+    }
+
+    pub fn transmittance(&self, ray: Ray, t_max: f32, rng: &mut Pcg) -> f32 {
+        //// How much light makes it through the volume along the ray up to t_max
+        let boundary = match &self.boundary {
+            None => return self.medium.transmittance(ray, 0., t_max, rng),
+            Some(boundary) => boundary,
+        };
+
+        let mut transmittance = 1.;
+        for Span(entry, exit) in boundary.intervals(ray) {
+            let (start, end) = (entry.t.max(0.), exit.t.min(t_max));
+            if start < end {
+                transmittance *= self.medium.transmittance(ray, start, end, rng);
+            }
+        }
+        transmittance
+    }
+
+    pub fn grid(medium: Heterogeneous) -> Volume {
+        //// A heterogeneous medium filling its box
+        Volume {
+            boundary: Some(Box::new(AABB {
+                min: medium.min,
+                max: medium.max,
+                texture: Arc::new(Colour::white()),
+                material: LAMBERT,
+            })),
+            medium: Box::new(medium),
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////
+//// How thick a heterogeneous medium is through its box; p
+//// is from (0, 0, 0) at one corner to (1, 1, 1) at the other
+/////////////////////////////////////////////////////////////
+pub trait Density: Send + Sync {
+    fn density(&self, p: Vec3) -> f32;
+    fn max(&self) -> f32; // Can't be thicker than this anywhere
+}
+
+//////////////////////////////////////////////////////////////////
+//// Densities stored at the corners of a grid of voxels, blended
+//// in between. Files are a line of text "grid <nx> <ny> <nz>",
+//// then nx * ny * nz little-endian 32 bit floats, x changing
+//// fastest and z slowest. Every side needs at least one voxel, and
+//// densities can't be negative
+//////////////////////////////////////////////////////////////////
+pub struct Grid {
+    pub size: (usize, usize, usize),
+    pub values: Vec<f32>,
+    max: f32,
+}
+
+impl Grid {
+    pub fn new(size: (usize, usize, usize), values: Vec<f32>) -> Result<Grid, String> {
+        if size.0 == 0 || size.1 == 0 || size.2 == 0 {
+            return Err(format!("Grid sizes must be at least 1, found {} {} {}", size.0, size.1, size.2));
+        }
+        let count = size.0.checked_mul(size.1).and_then(|n| n.checked_mul(size.2)).ok_or("Grid too big")?;
+        if values.len() != count {
+            return Err(format!("Expected {} values in the grid, found {}", count, values.len()));
+        }
+        // Trackers need densities they can compare against the most there is
+        if let Some(bad) = values.iter().find(|value| !value.is_finite() || **value < 0.) {
+            return Err(format!("Grid densities can't be negative or infinite, found {}", bad));
+        }
+        let max = values.iter().cloned().fold(0., f32::max);
+        Ok(Grid {size, values, max})
+    }
+
+    pub fn load(filename: &str) -> Result<Grid, String> {
+        let bytes = std::fs::read(filename).map_err(|e| format!("{}: {}", filename, e))?;
+
+        let header_end = bytes.iter().position(|&b| b == b'\n').ok_or("Grid file has no header")?;
+        let header = String::from_utf8_lossy(&bytes[..header_end]);
+        let words: Vec<&str> = header.split_whitespace().collect();
+
+        if words.len() != 4 || words[0] != "grid" {
+            return Err(format!("Expected 'grid <nx> <ny> <nz>', found '{}'", header));
+        }
+        let mut size = [0usize; 3];
+        for i in 0..3 {
+            size[i] = words[i + 1].parse().map_err(|_| format!("Bad grid size '{}'", words[i + 1]))?;
+        }
+
+        if size.contains(&0) {
+            return Err(format!("Grid sizes must be at least 1, found '{}'", header));
+        }
+
+        let data = &bytes[header_end + 1..];
+        let count = size[0].checked_mul(size[1]).and_then(|n| n.checked_mul(size[2])).ok_or("Grid too big")?;
+        let length = count.checked_mul(4).ok_or("Grid too big")?;
+        if data.len() != length {
+            return Err(format!("Expected {} values in the grid, found {} bytes", count, data.len()));
+        }
+
+        let values = data.chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+
+        Grid::new((size[0], size[1], size[2]), values)
+    }
+
+    fn at(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[x + self.size.0 * (y + self.size.1 * z)]
+    }
+}
+
+impl Density for Grid {
+    fn density(&self, p: Vec3) -> f32 {
+        // Position in voxels, and the corner below it
+        let mut corner = [0; 3];
+        let mut frac = [0.; 3];
+        let size = [self.size.0, self.size.1, self.size.2];
+
+        for i in 0..3 {
+            let x = (p.value[i] * (size[i] - 1) as f32).clamp(0., (size[i] - 1) as f32);
+            corner[i] = (x.floor() as usize).min(size[i].saturating_sub(2));
+            frac[i] = x - corner[i] as f32;
+        }
+
+        let [x, y, z] = corner;
+        let [u, v, w] = frac;
+        // Don't step off the end of one voxel wide grids
+        let (x1, y1, z1) = ((x + 1).min(size[0] - 1), (y + 1).min(size[1] - 1), (z + 1).min(size[2] - 1));
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        lerp(
+            lerp(
+                lerp(self.at(x, y, z), self.at(x1, y, z), u),
+                lerp(self.at(x, y1, z), self.at(x1, y1, z), u),
+                v),
+            lerp(
+                lerp(self.at(x, y, z1), self.at(x1, y, z1), u),
+                lerp(self.at(x, y1, z1), self.at(x1, y1, z1), u),
+                v),
+            w)
+    }
+
+    fn max(&self) -> f32 {
+        self.max
+    }
+}
+
+///////////////////////////////////////////////////////////////////
+//// Clouds from fractal noise; anything below `coverage` is clear,
+//// and it fades out towards the sides of the box
+///////////////////////////////////////////////////////////////////
+pub struct NoiseDensity {
+    pub frequency: f32, // Lumps across the box
+    pub octaves: u32,
+    pub coverage: f32, // Between 0 and 1
+}
+
+impl Density for NoiseDensity {
+    fn density(&self, p: Vec3) -> f32 {
+        let edge = p.value.iter().fold(1., |edge: f32, x| edge.min(smooth_edge(*x)));
+        let noise = fbm(value_noise, p * self.frequency, self.octaves);
+        ((noise - self.coverage) / (1. - self.coverage)).max(0.) * edge
+    }
+
+    fn max(&self) -> f32 {
+        1.
+    }
+}
+
+fn smooth_edge(x: f32) -> f32 {
+    // 0 at the sides of the box, going up to 1 a fifth of the way in
+    let t = (x.min(1. - x) * 5.).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+///////////////////////////////////////////////////////////////////
+//// A medium whose density changes from place to place, through
+//// the box from min to max. Emission (for fire) is how brightly what
+//// it absorbs glows; collisions are already more likely where it's
+//// thicker, so thinner parts glow less without scaling it down
+///////////////////////////////////////////////////////////////////
+pub struct Heterogeneous {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub density: Box<dyn Density>,
+    pub scale: f32, // Density multiplier
+    pub albedo: Colour,
+    pub emission: Colour,
+    pub anisotropy: f32,
+}
+
+impl Heterogeneous {
+    fn density(&self, p: Vec3) -> f32 {
+        let local = p - self.min;
+        let size = self.max - self.min;
+        let local = Vec3::new(local.x() / size.x(), local.y() / size.y(), local.z() / size.z());
+        self.scale * self.density.density(local)
+    }
+
+    fn step(&self, majorant: f32, length: f32, rng: &mut Pcg) -> f32 {
+        // Distance (as t along the ray) to the next tentative collision
+        -(1. - rng.next_float()).ln() / majorant / length
+    }
+}
+
+impl Medium for Heterogeneous {
+    fn sample(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut Pcg) -> Option<Collision> {
+        //// Delta tracking; pretend it's all as thick as the thickest part,
+        //// and treat collisions with the made up extra as not happening
+        let majorant = self.scale * self.density.max();
+        if majorant <= 0. {
+            return None;
+        }
+        let length = ray.d.length();
+        let mut t = t_min;
+
+        loop {
+            t += self.step(majorant, length, rng);
+            if t >= t_max {
+                return None;
+            }
+
+            let density = self.density(ray.at(t));
+            if rng.next_float() < density / majorant {
+                return Some(Collision {
+                    t,
+                    albedo: self.albedo,
+                    emission: self.emission,
+                });
+            }
+        }
+    }
+
+    fn transmittance(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut Pcg) -> f32 {
+        //// Ratio tracking; the same steps as delta tracking, but rather than
+        //// stopping at a collision keep going, weighted by the chance of missing
+        let majorant = self.scale * self.density.max();
+        if majorant <= 0. {
+            return 1.;
+        }
+        let length = ray.d.length();
+        let mut t = t_min;
+        let mut transmittance = 1.;
+
+        loop {
+            t += self.step(majorant, length, rng);
+            if t >= t_max {
+                return transmittance;
+            }
+            transmittance *= 1. - self.density(ray.at(t)) / majorant;
+        }
+    }
+
+    fn anisotropy(&self) -> f32 {
+        self.anisotropy
+    }
+}
+
+pub fn henyey_greenstein_phase(cos: f32, g: f32) -> f32 {
+    //// How much of the light going one way gets scattered off at
+    //// an angle (with this cosine) to it, per unit solid angle
+    let denominator = 1. + g*g - 2.*g*cos;
+    (1. - g*g) / (4. * PI * denominator * denominator.sqrt())
+}
+
+pub fn henyey_greenstein(d: Vec3, g: f32, rng: &mut Pcg) -> Vec3 {
+    //// A new direction for a ray going along d (normalised) after being
+    //// scattered, from the Henyey-Greenstein phase function
+    let u = rng.next_float();
+    let cos = if g.abs() < 0.001 {
+        1. - 2. * u
+    } else {
+        let s = (1. - g*g) / (1. - g + 2.*g*u);
+        (1. + g*g - s*s) / (2.*g)
+    };
+    let sin = (1. - cos*cos).max(0.).sqrt();
+    let azimuth = rng.next_float() * PI * 2.;
+
+    let (side, up) = basis(d);
+
+    d * cos + (side * azimuth.cos() + up * azimuth.sin()) * sin
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, bytes: &[u8]) -> Result<Grid, String> {
+        //// Load a grid from a file written for the test
+        let path = std::env::temp_dir().join(format!("raytracer-test-{}-{}.grid", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let grid = Grid::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        grid
+    }
+
+    fn file(header: &str, values: &[f32]) -> Vec<u8> {
+        let mut bytes = format!("{}\n", header).into_bytes();
+        for value in values {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn error(result: Result<Grid, String>) -> String {
+        result.err().expect("should not have loaded")
+    }
+
+    #[test]
+    fn loads() {
+        let grid = load("good", &file("grid 2 1 1", &[0.25, 0.75])).unwrap();
+        assert_eq!(grid.size, (2, 1, 1));
+        assert_eq!(grid.max(), 0.75);
+        assert_eq!(grid.density(Vec3::new(0.5, 0.5, 0.5)), 0.5);
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(error(load("no-header", b"grid 1 1 1")), "Grid file has no header");
+        assert_eq!(error(load("bad-header", &file("voxels 1 1 1", &[1.]))), "Expected 'grid <nx> <ny> <nz>', found 'voxels 1 1 1'");
+        assert_eq!(error(load("too-few", &file("grid 1 1", &[1.]))), "Expected 'grid <nx> <ny> <nz>', found 'grid 1 1'");
+        assert_eq!(error(load("bad-size", &file("grid 1 x 1", &[1.]))), "Bad grid size 'x'");
+        assert_eq!(error(load("negative", &file("grid 1 -1 1", &[1.]))), "Bad grid size '-1'");
+        assert_eq!(error(load("empty", &file("grid 0 4 4", &[]))), "Grid sizes must be at least 1, found 'grid 0 4 4'");
+        assert_eq!(error(load("short", &file("grid 2 2 1", &[1., 2., 3.]))), "Expected 4 values in the grid, found 12 bytes");
+        assert_eq!(error(load("ragged", &[file("grid 1 1 1", &[1.]), vec![0]].concat())), "Expected 1 values in the grid, found 5 bytes");
+        assert_eq!(error(load("huge", &file("grid 99999999999 99999999999 9", &[1.]))), "Grid too big");
+        assert_eq!(error(load("huge-bytes", &file(&format!("grid {} 1 1", usize::MAX / 2), &[1.]))), "Grid too big");
+        assert_eq!(error(load("negative-density", &file("grid 2 1 1", &[0.5, -0.5]))), "Grid densities can't be negative or infinite, found -0.5");
+        assert_eq!(error(load("nan-density", &file("grid 1 1 1", &[f32::NAN]))), "Grid densities can't be negative or infinite, found NaN");
+        assert_eq!(error(load("infinite-density", &file("grid 1 1 1", &[f32::INFINITY]))), "Grid densities can't be negative or infinite, found inf");
+        assert!(error(Grid::load("/nonexistent/cloud.grid")).starts_with("/nonexistent/cloud.grid: "));
+    }
+
+    #[test]
+    fn new_checks_sizes() {
+        assert_eq!(error(Grid::new((1, 0, 1), vec![])), "Grid sizes must be at least 1, found 1 0 1");
+        assert_eq!(error(Grid::new((2, 1, 1), vec![1.])), "Expected 2 values in the grid, found 1");
+        assert_eq!(error(Grid::new((usize::MAX, 2, 1), vec![1.])), "Grid too big");
+        assert_eq!(error(Grid::new((1, 1, 1), vec![-1.])), "Grid densities can't be negative or infinite, found -1");
+        assert!(Grid::new((1, 1, 1), vec![1.]).is_ok());
+    }
+
+    #[test]
+    fn one_voxel_wide() {
+        let grid = Grid::new((1, 2, 1), vec![0.5, 1.]).unwrap();
+        assert_eq!(grid.density(Vec3::new(0., 0., 0.)), 0.5);
+        assert_eq!(grid.density(Vec3::new(1., 1., 1.)), 1.);
+    }
+}
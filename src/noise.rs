@@ -0,0 +1,154 @@
+use super::vector::*;
+
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    //// Scramble a lattice point into a pseudorandom number
+    let mut h = (x as u32).wrapping_mul(0x8da6b343)
+        ^ (y as u32).wrapping_mul(0xd8163841)
+        ^ (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    h
+}
+
+fn lattice(x: i32, y: i32, z: i32) -> f32 {
+    // A value in [0, 1) for each lattice point
+    (hash(x, y, z) >> 8) as f32 / (1 << 24) as f32
+}
+
+fn smooth(t: f32) -> f32 {
+    t * t * (3. - 2. * t)
+}
+
+pub fn value_noise(p: Vec3) -> f32 {
+    //// Smoothly interpolated random values at integer points, in [0, 1)
+    let (x, y, z) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (i, j, k) = (x as i32, y as i32, z as i32);
+    let (u, v, w) = (smooth(p.x() - x), smooth(p.y() - y), smooth(p.z() - z));
+
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+    lerp(
+        lerp(
+            lerp(lattice(i, j, k), lattice(i + 1, j, k), u),
+            lerp(lattice(i, j + 1, k), lattice(i + 1, j + 1, k), u),
+            v),
+        lerp(
+            lerp(lattice(i, j, k + 1), lattice(i + 1, j, k + 1), u),
+            lerp(lattice(i, j + 1, k + 1), lattice(i + 1, j + 1, k + 1), u),
+            v),
+        w)
+}
+
+pub fn fbm(noise: fn(Vec3) -> f32, p: Vec3, octaves: u32) -> f32 {
+    //// Fractal Brownian motion; layers of noise, each twice as
+    //// detailed and half as strong as the last. Stays in the
+    //// same range as the noise
+    let mut total = 0.;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.;
+    let mut sum = 0.;
+
+    for _ in 0..octaves {
+        total += amplitude * noise(p * frequency);
+        sum += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.;
+    }
+
+    total / sum
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+fn gradient(hash: u32, x: f32, y: f32, z: f32) -> f32 {
+    // Dot product with one of the 12 directions to the edges of a cube
+    let h = hash & 15;
+    let u = if h < 8 {x} else {y};
+    let v = if h < 4 {y} else if h == 12 || h == 14 {x} else {z};
+    (if h & 1 == 0 {u} else {-u}) + (if h & 2 == 0 {v} else {-v})
+}
+
+pub fn perlin(p: Vec3) -> f32 {
+    //// Ken Perlin's improved gradient noise, roughly in [-1, 1]
+    let (x, y, z) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (i, j, k) = (x as i32, y as i32, z as i32);
+    let (x, y, z) = (p.x() - x, p.y() - y, p.z() - z);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let g = |di: i32, dj: i32, dk: i32| {
+        gradient(hash(i + di, j + dj, k + dk), x - di as f32, y - dj as f32, z - dk as f32)
+    };
+
+    lerp(
+        lerp(
+            lerp(g(0, 0, 0), g(1, 0, 0), u),
+            lerp(g(0, 1, 0), g(1, 1, 0), u),
+            v),
+        lerp(
+            lerp(g(0, 0, 1), g(1, 0, 1), u),
+            lerp(g(0, 1, 1), g(1, 1, 1), u),
+            v),
+        w)
+}
+
+pub fn simplex(p: Vec3) -> f32 {
+    //// Simplex noise; gradients blended over the corners of the tetrahedron
+    //// the point is in, rather than a whole cube. Roughly in [-1, 1]
+    const SKEW: f32 = 1. / 3.;
+    const UNSKEW: f32 = 1. / 6.;
+
+    // Which skewed cube, and where in it
+    let s = (p.x() + p.y() + p.z()) * SKEW;
+    let (i, j, k) = ((p.x() + s).floor(), (p.y() + s).floor(), (p.z() + s).floor());
+    let t = (i + j + k) * UNSKEW;
+    let first = Vec3::new(p.x() - i + t, p.y() - j + t, p.z() - k + t);
+
+    // Which of the six tetrahedra in the cube; step along the biggest axis first
+    let (x, y, z) = (first.x(), first.y(), first.z());
+    let (step1, step2) = if x >= y {
+        if y >= z { ((1, 0, 0), (1, 1, 0)) }
+        else if x >= z { ((1, 0, 0), (1, 0, 1)) }
+        else { ((0, 0, 1), (1, 0, 1)) }
+    } else if y < z { ((0, 0, 1), (0, 1, 1)) }
+    else if x < z { ((0, 1, 0), (0, 1, 1)) }
+    else { ((0, 1, 0), (1, 1, 0)) };
+
+    let (i, j, k) = (i as i32, j as i32, k as i32);
+    let corners = [(0, 0, 0), step1, step2, (1, 1, 1)];
+
+    let mut total = 0.;
+    for (n, (di, dj, dk)) in corners.iter().enumerate() {
+        let offset = Vec3::new(*di as f32, *dj as f32, *dk as f32) - Vec3::new(1., 1., 1.) * (n as f32 * UNSKEW);
+        let d = first - offset;
+        let falloff = 0.6 - dot(d, d);
+        if falloff > 0. {
+            total += falloff.powi(4) * gradient(hash(i + di, j + dj, k + dk), d.x(), d.y(), d.z());
+        }
+    }
+
+    32. * total
+}
+
+pub fn turbulence(noise: fn(Vec3) -> f32, p: Vec3, octaves: u32) -> f32 {
+    //// Like fbm, but with every layer folded to be positive,
+    //// which makes sharp creases where the noise crosses 0
+    let mut total = 0.;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.;
+    let mut sum = 0.;
+
+    for _ in 0..octaves {
+        total += amplitude * noise(p * frequency).abs();
+        sum += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.;
+    }
+
+    total / sum
+}
@@ -0,0 +1,441 @@
+use std::thread;
+use super::vector::*;
+use super::colour::*;
+use super::primitive::*;
+use super::ray::*;
+use super::scene::*;
+use super::sampler::*;
+use super::rng::*;
+use super::aov::*;
+use super::light::*;
+use super::integrator::*;
+
+//////////////////////////////////////////////////////////////////////////
+//// Photon mapping (Jensen's). Before rendering, light is traced out from
+//// the glowing objects and the point and spot lights as photons, and
+//// wherever one lands on something diffuse it's kept in the global map.
+//// Ones which only went through glass or off mirrors on the way there go
+//// in the caustic map as well. Camera rays follow glass and mirrors until
+//// they reach something diffuse, where the lights are sampled directly,
+//// caustics come from how many photons landed nearby, and the rest is
+//// gathered from one more ray out (a final gather) and the photons
+//// around wherever it lands. Photons can't come from the sky, so it only
+//// lights what the gather rays find it from.
+////
+//// With more than one pass it's progressive (Knaus and Zwicker's): each
+//// pass has photons of its own, gathered from a smaller radius than the
+//// pass before, and each camera ray uses one of them at random, so the
+//// more passes there are the less blurry the lighting gets. Volumes are
+//// left out, and directional lights don't give off photons
+//////////////////////////////////////////////////////////////////////////
+pub struct PhotonMapper {
+    pub photons: usize, // Traced for each pass
+    pub radius: f32, // How far around a point photons are gathered from, in the global map
+    pub caustic_radius: f32, // And in the caustic map, which needs to be sharper
+    pub passes: u32,
+    pub alpha: f32, // Between 0 and 1; the lower it is, the faster the radius shrinks
+    pub depth: u32, // Most bounces photons, and camera rays through glass, can make
+    pub seed: u32,
+    emitters: Vec<usize>, // Objects which photons start from
+    maps: Vec<Pass>,
+}
+
+impl Default for PhotonMapper {
+    fn default() -> PhotonMapper {
+        PhotonMapper {
+            photons: 200000,
+            radius: 0.1,
+            caustic_radius: 0.05,
+            passes: 1,
+            alpha: 0.7,
+            depth: 8,
+            seed: 0,
+            emitters: vec![],
+            maps: vec![],
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Photon {
+    point: Vec3,
+    direction: Vec3, // Which way it was going
+    power: Colour,
+    axis: usize, // Which axis the kd-tree is split along here
+}
+
+// One pass's photon maps, and how far to gather from them
+struct Pass {
+    global: PhotonMap,
+    caustic: PhotonMap,
+    radius: f32,
+    caustic_radius: f32,
+}
+
+//////////////////////////////////////////////////////////////////////////
+//// Photons in a balanced kd-tree, laid out in the list itself; the
+//// middle photon of any stretch of the list splits the rest of it in
+//// two along one axis (whichever they're most spread out along), with
+//// the ones before it on one side and the ones after it on the other
+//////////////////////////////////////////////////////////////////////////
+struct PhotonMap {
+    photons: Vec<Photon>,
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>) -> PhotonMap {
+        balance(&mut photons);
+        PhotonMap {photons}
+    }
+
+    fn estimate(&self, hit: &SurfaceInteraction, wo: Vec3, radius: f32) -> Colour {
+        //// Light reflected off a diffuse surface towards wo, from the photons around the point
+        let side = dot(wo, hit.normal);
+        let mut power = Colour::black();
+        search(&self.photons, hit.point, radius * radius, &mut |photon| {
+            // Only ones which landed on this side; not through from behind a thin wall
+            if dot(photon.direction, hit.normal) * side < 0. {
+                power += photon.power;
+            }
+        });
+        let material = hit.material;
+        hit.colour * power * (material.albedo * material.diffuse / (PI * PI * radius * radius))
+    }
+}
+
+fn balance(photons: &mut [Photon]) {
+    if photons.len() < 2 {
+        return;
+    }
+    let spread = |axis: usize| {
+        let values = photons.iter().map(|photon| photon.point.value[axis]);
+        values.clone().fold(f32::NEG_INFINITY, f32::max) - values.fold(f32::INFINITY, f32::min)
+    };
+    let spreads = [spread(0), spread(1), spread(2)];
+    let axis = (0..3).max_by(|&a, &b| spreads[a].total_cmp(&spreads[b])).unwrap();
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| a.point.value[axis].total_cmp(&b.point.value[axis]));
+    photons[middle].axis = axis;
+
+    let (before, after) = photons.split_at_mut(middle);
+    balance(before);
+    balance(&mut after[1..]);
+}
+
+fn search(photons: &[Photon], p: Vec3, radius_squared: f32, found: &mut impl FnMut(&Photon)) {
+    //// Every photon within the radius of p
+    if photons.is_empty() {
+        return;
+    }
+    let middle = photons.len() / 2;
+    let photon = &photons[middle];
+    if (photon.point - p).length_squared() <= radius_squared {
+        found(photon);
+    }
+
+    // The side p is on first, then the other side if the radius reaches over
+    let apart = p.value[photon.axis] - photon.point.value[photon.axis];
+    let (before, after) = (&photons[..middle], &photons[middle + 1..]);
+    let (near, far) = if apart < 0. {(before, after)} else {(after, before)};
+    search(near, p, radius_squared, found);
+    if apart * apart <= radius_squared {
+        search(far, p, radius_squared, found);
+    }
+}
+
+impl PhotonMapper {
+    fn emit_from_object(&self, scene: &Scene, id: usize, sampler: &mut dyn Sampler) -> Option<EmittedRay> {
+        //// Light leaving a point on a glowing object; by cos around the
+        //// normal, on either side if it glows on both
+        let sample = scene.objects[id].sample_surface(sampler.get_2d())?;
+        let emission = sample.material.emission.as_ref()?;
+        let side = sampler.get_1d();
+        let (normal, side_pdf) = if !emission.two_sided {
+            (sample.normal, 1.)
+        } else if side < 0.5 {
+            (sample.normal, 0.5)
+        } else {
+            (-sample.normal, 0.5)
+        };
+        let direction = cosine_sample(normal, sampler.get_2d());
+
+        // cos over the pdf, which is cos / pi (for the direction) over the area
+        let radiance = emission.radiance(sample.uv, sample.point, dot(direction, sample.normal) > 0.);
+        Some(EmittedRay {
+            origin: sample.point + normal * 0.01,
+            direction,
+            power: radiance * (PI * sample.area / side_pdf),
+        })
+    }
+
+    fn trace_photon(&self, scene: &Scene, lights: &[usize], sampler: &mut dyn Sampler, global: &mut Vec<Photon>, caustic: &mut Vec<Photon>) {
+        //// Send one photon out from a light (each equally likely), and keep it
+        //// wherever it lands on something diffuse
+        let sources = self.emitters.len() + lights.len();
+        if sources == 0 {
+            return;
+        }
+        let choice = ((sampler.get_1d() * sources as f32) as usize).min(sources - 1);
+        let emitted = if choice < self.emitters.len() {
+            self.emit_from_object(scene, self.emitters[choice], sampler)
+        } else {
+            scene.lights[lights[choice - self.emitters.len()]].emit(sampler.get_2d())
+        };
+        let Some(emitted) = emitted else {return};
+
+        // Photons are all traced at the start of the shutter
+        let mut ray = Ray::new(emitted.origin, emitted.direction, 0.);
+        let mut power = emitted.power * (sources as f32 / self.photons as f32);
+        let (mut specular, mut diffuse) = (false, false); // What it's bounced off so far
+
+        for _ in 0..self.depth {
+            let Some(hit) = scene.intersect(ray) else {break};
+            let mat = hit.material;
+            let d = ray.d.normalise();
+
+            let (newd, factor) = if mat.diffuse > 0. {
+                let photon = Photon {point: hit.point, direction: d, power, axis: 0};
+                global.push(photon);
+                if specular && !diffuse {
+                    caustic.push(photon);
+                }
+                diffuse = true;
+
+                // Back out on the side it came from
+                let n = if dot(hit.shading, d) < 0. {hit.shading} else {-hit.shading};
+                (cosine_sample(n, sampler.get_2d()), hit.colour * (mat.albedo * mat.diffuse))
+            } else {
+                specular = true;
+                bounce(&hit, d, sampler)
+            };
+
+            // Russian roulette; fewer photons carry on, but each with about as much power
+            let survive = factor.max_element().min(1.);
+            if survive <= 0. || sampler.get_1d() >= survive {
+                break;
+            }
+            power = power * factor / survive;
+            ray = offset(&hit, newd, ray.time);
+        }
+    }
+
+    fn trace_pass(&self, scene: &Scene, lights: &[usize], pass: u32, threads: usize) -> (Vec<Photon>, Vec<Photon>) {
+        //// Every photon for one pass, split between the threads; each photon has its own
+        //// random numbers, so the maps come out the same however many threads there are
+        let each = self.photons.div_ceil(threads);
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads).map(|thread| scope.spawn(move || {
+                let mut sampler = Independent::new(self.seed);
+                let (mut global, mut caustic) = (vec![], vec![]);
+                for i in thread * each..((thread + 1) * each).min(self.photons) {
+                    // Well away from any pixel's random numbers
+                    sampler.start_pixel_sample((u32::MAX - pass, u32::MAX), i as u32);
+                    self.trace_photon(scene, lights, &mut sampler, &mut global, &mut caustic);
+                }
+                (global, caustic)
+            })).collect();
+
+            let (mut global, mut caustic) = (vec![], vec![]);
+            for handle in handles {
+                let (g, c) = handle.join().unwrap();
+                global.extend(g);
+                caustic.extend(c);
+            }
+            (global, caustic)
+        })
+    }
+
+    fn gather(&self, scene: &Scene, pass: &Pass, hit: &SurfaceInteraction, wo: Vec3, time: f32, sampler: &mut dyn Sampler) -> Colour {
+        //// Light onto a diffuse surface from everything but the lights and caustics (which
+        //// are counted already), through one ray out from it; the sky if it gets away,
+        //// or the photons around wherever it lands on something diffuse
+        let mat = hit.material;
+        let n = if dot(hit.shading, wo) > 0. {hit.shading} else {-hit.shading};
+        let mut ray = offset(hit, cosine_sample(n, sampler.get_2d()), time);
+        // The BSDF times cos, over the pdf
+        let mut beta = hit.colour * (mat.albedo * mat.diffuse);
+
+        for _ in 0..self.depth {
+            let d = ray.d.normalise();
+            let Some(next) = scene.intersect(ray) else {
+                return beta * scene.background.radiance(d);
+            };
+            if next.material.diffuse > 0. {
+                return beta * pass.global.estimate(&next, -d, pass.radius);
+            }
+            let (newd, factor) = bounce(&next, d, sampler);
+            beta = beta * factor;
+            ray = offset(&next, newd, time);
+        }
+        Colour::black()
+    }
+
+    fn emitter_light(&self, scene: &Scene, hit: &SurfaceInteraction, wo: Vec3, time: f32, sampler: &mut dyn Sampler) -> Colour {
+        //// Light straight from a point picked on one of the glowing objects
+        if self.emitters.is_empty() {
+            return Colour::black();
+        }
+        let choice = ((sampler.get_1d() * self.emitters.len() as f32) as usize).min(self.emitters.len() - 1);
+        let u = sampler.get_2d();
+        let Some(sample) = scene.objects[self.emitters[choice]].sample_surface(u) else {return Colour::black()};
+        let Some(emission) = sample.material.emission.as_ref() else {return Colour::black()};
+
+        let to = sample.point - hit.point;
+        let distance = to.length();
+        let wi = to / distance;
+        // Diffuse surfaces only reflect
+        if dot(wi, hit.normal) * dot(wo, hit.normal) <= 0. {
+            return Colour::black();
+        }
+        let radiance = emission.radiance(sample.uv, sample.point, dot(-wi, sample.normal) > 0.);
+        if radiance.max_element() <= 0. {
+            return Colour::black();
+        }
+        if scene.intersect(offset(hit, wi, time)).is_some_and(|blocker| blocker.t < distance - 0.02) {
+            return Colour::black();
+        }
+
+        // Over the pdf (per area) of having picked that point
+        let pdf = 1. / (self.emitters.len() as f32 * sample.area);
+        let mat = hit.material;
+        let cos = dot(wi, hit.shading).abs() * dot(wi, sample.normal).abs();
+        hit.colour * radiance * (mat.albedo * mat.diffuse / PI * cos / (distance * distance * pdf))
+    }
+}
+
+impl Integrator for PhotonMapper {
+    fn preprocess(&mut self, scene: &Scene, threads: usize) {
+        self.emitters = scene.emitters();
+        let lights: Vec<usize> = (0..scene.lights.len()).filter(|&i| scene.lights[i].emit((0.5, 0.5)).is_some()).collect();
+
+        let (mut radius, mut caustic_radius) = (self.radius, self.caustic_radius);
+        let mut maps = vec![];
+        for pass in 0..self.passes.max(1) {
+            let (global, caustic) = self.trace_pass(scene, &lights, pass, threads.max(1));
+            maps.push(Pass {global: PhotonMap::new(global), caustic: PhotonMap::new(caustic), radius, caustic_radius});
+
+            // The area shrinks by (i + alpha) / (i + 1) each pass
+            let shrink = ((pass as f32 + 1. + self.alpha) / (pass as f32 + 2.)).sqrt();
+            radius *= shrink;
+            caustic_radius *= shrink;
+        }
+        self.maps = maps;
+    }
+
+    fn li(&self, ray: Ray, context: &Context, sampler: &mut dyn Sampler, rng: &mut Pcg, aov: Option<&mut AovSample>) -> Colour {
+        let scene = context.scene;
+        let pass = &self.maps[((rng.next_float() * self.maps.len() as f32) as usize).min(self.maps.len() - 1)];
+
+        let mut ray = ray;
+        let mut aov = aov;
+        let mut beta = Colour::white();
+        // Lighting on the first surface, for the AOVs; anything after it is indirect
+        let (mut emission, mut direct, mut indirect) = (Colour::black(), Colour::black(), Colour::black());
+
+        for depth in 0..self.depth {
+            let d = ray.d.normalise();
+            let Some(hit) = scene.intersect(ray) else {
+                let sky = beta * scene.background.radiance(d);
+                if depth == 0 {emission += sky} else {indirect += sky}
+                break;
+            };
+            let mat = hit.material;
+
+            if depth == 0 {
+                if let Some(aov) = aov.as_deref_mut() {
+                    aov.depth = Some(hit.t * ray.d.length());
+                    aov.normal = hit.shading;
+                    aov.albedo = hit.colour * mat.albedo;
+                    aov.object = Some(hit.id);
+                    aov.material = Some(material_id(mat));
+                }
+            }
+
+            let emitted = beta * mat.emission.as_ref().map_or(Colour::black(), |emission| emission.radiance(hit.uv, hit.point, hit.front_face));
+            if depth == 0 {emission += emitted} else {indirect += emitted}
+
+            if mat.diffuse > 0. {
+                let lit = direct_light(scene, &hit, ray.time, sampler, rng) + self.emitter_light(scene, &hit, -d, ray.time, sampler);
+                let gathered = pass.caustic.estimate(&hit, -d, pass.caustic_radius) + self.gather(scene, pass, &hit, -d, ray.time, sampler);
+                if depth == 0 {
+                    direct += beta * lit;
+                    indirect += beta * gathered;
+                } else {
+                    indirect += beta * (lit + gathered);
+                }
+                break;
+            }
+
+            let (newd, factor) = bounce(&hit, d, sampler);
+            beta = beta * factor;
+            ray = offset(&hit, newd, ray.time);
+        }
+
+        if let Some(aov) = aov {
+            aov.direct = direct;
+            aov.indirect = indirect;
+            aov.emission = emission;
+        }
+        emission + direct + indirect
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn photons(count: usize, rng: &mut Pcg) -> Vec<Photon> {
+        //// Photons spread through a box, with some landing in exactly the same
+        //// places and on the same planes (like on a floor), which splits have to handle
+        (0..count).map(|i| {
+            let mut point = Vec3::new(rng.next_float(), rng.next_float(), rng.next_float()) * 4.;
+            if i % 5 == 0 {
+                point = Vec3::new(point.x(), 0., point.z());
+            }
+            if i % 7 == 0 {
+                point = Vec3::new(1., 2., 3.);
+            }
+            Photon {point, direction: Vec3::new(0., -1., 0.), power: Colour::white(), axis: 0}
+        }).collect()
+    }
+
+    fn key(point: Vec3) -> [u32; 3] {
+        point.value.map(f32::to_bits)
+    }
+
+    #[test]
+    fn search_finds_what_brute_force_does() {
+        let mut rng = Pcg::new(4839, 0);
+        let all = photons(2000, &mut rng);
+        let points: Vec<Vec3> = all.iter().map(|photon| photon.point).collect();
+        let map = PhotonMap::new(all);
+
+        for i in 0..200 {
+            let p = Vec3::new(rng.next_float(), rng.next_float(), rng.next_float()) * 5. - Vec3::new(0.5, 0.5, 0.5);
+            let p = if i % 10 == 0 {Vec3::new(1., 2., 3.)} else {p};
+            let radius_squared = [0.01, 0.1, 0.5, 2.][i % 4];
+
+            let mut found = vec![];
+            search(&map.photons, p, radius_squared, &mut |photon| found.push(key(photon.point)));
+            let mut expected: Vec<[u32; 3]> = points.iter()
+                .filter(|&&point| (point - p).length_squared() <= radius_squared)
+                .map(|&point| key(point))
+                .collect();
+
+            found.sort();
+            expected.sort();
+            assert_eq!(found, expected, "around {:?} within {}", p.value, radius_squared);
+        }
+    }
+
+    #[test]
+    fn search_handles_tiny_maps() {
+        for count in 0..4 {
+            let map = PhotonMap::new(photons(count, &mut Pcg::new(1, 0)));
+            let mut found = 0;
+            search(&map.photons, Vec3::new(2., 2., 2.), 100., &mut |_| found += 1);
+            assert_eq!(found, count);
+        }
+    }
+}
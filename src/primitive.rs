@@ -1,90 +1,263 @@
-use std::f32::INFINITY;
 use super::vector::*;
+use super::ray::*;
 use super::material::*;
 use super::colour::*;
+use super::sdf::*;
 use super::texture::*;
+use std::sync::Arc;
+use std::cell::Cell;
+
+/////////////////////////////////////////////////////////////////////
+//// Everything about where a ray meets a surface. The geometric
+//// normal is normalised and always points out of the object; whether
+//// the ray came from that side is front_face. The shading normal is
+//// the same until the material's normal and bump maps are applied
+/////////////////////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct SurfaceInteraction<'a> {
+    pub t: f32, // Distance, in multiples of the ray direction
+    pub point: Vec3,
+    pub normal: Vec3, // Geometric normal
+    pub shading: Vec3, // Shading normal
+    pub uv: (f32, f32),
+    pub dpdu: Vec3, // How the point moves along the surface as u changes
+    pub dpdv: Vec3,
+    pub front_face: bool, // Whether the ray hit the outside
+    pub id: usize, // Which object in the scene was hit
+    pub material: &'a Material,
+    pub colour: Colour, // Texture colour at the point
+}
 
-//////////////////////////////////
-//// Holds information about a hit
-//////////////////////////////////
-#[derive(Copy, Clone)]
-pub struct Hit (
-    pub f32, // Distance
-    pub Vec3, // Point
-    pub Material, // Material
-    pub Colour, // Colour (at that point)
-    pub Vec3, // Normal
-);
+impl<'a> SurfaceInteraction<'a> {
+    pub fn new(ray: Ray, t: f32, normal: Vec3, mapping: ((f32, f32), Vec3, Vec3), material: &'a Material, colour: Colour) -> SurfaceInteraction<'a> {
+        //// Fills in everything that follows from the ray and normal
+        let normal = normal.normalise();
+        let (uv, dpdu, dpdv) = mapping;
+
+        SurfaceInteraction {
+            t,
+            point: ray.at(t),
+            normal,
+            shading: normal,
+            uv,
+            dpdu,
+            dpdv,
+            front_face: dot(ray.d, normal) < 0.,
+            id: 0,
+            material,
+            colour,
+        }
+    }
+
+    pub fn flip(&mut self) {
+        //// Turn the surface inside out
+        self.normal = -self.normal;
+        self.shading = -self.shading;
+        self.front_face = !self.front_face;
+    }
+}
 
 ///////////////////////////////////////////////
 //// An object (only has an intersection (???))
 ///////////////////////////////////////////////
-pub trait Object {
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit>;
+pub trait Object: Send + Sync {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>>;
+
+    // A point spread evenly over the surface; shapes which can do this can be
+    // used as lights which light is traced out from and aimed at
+    fn sample_surface(&self, _: (f32, f32)) -> Option<SurfaceSample<'_>> {
+        None
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//// How much work finding hits has taken on this thread; one for each
+//// object tried and each step a marched shape takes. It only ever
+//// goes up, so the work for something is the difference across it
+/////////////////////////////////////////////////////////////////////
+thread_local! {
+    static WORK: Cell<u64> = const {Cell::new(0)};
+}
+
+pub fn count_work(amount: u64) {
+    WORK.with(|work| work.set(work.get() + amount));
+}
+
+pub fn work_done() -> u64 {
+    WORK.with(Cell::get)
+}
+
+//////////////////////////////////////////////////////////
+//// A point picked on an object's surface, and how big the
+//// surface it was picked from is (so what its pdf was)
+//////////////////////////////////////////////////////////
+pub struct SurfaceSample<'a> {
+    pub point: Vec3,
+    pub normal: Vec3, // Outwards, and normalised
+    pub uv: (f32, f32),
+    pub area: f32,
+    pub material: &'a Material,
+}
+
+///////////////////////////////////////////////////////////////
+//// A stretch of a ray spent inside a solid: the hit going in,
+//// and the hit coming out (normals on both point outwards)
+///////////////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct Span<'a> (
+    pub SurfaceInteraction<'a>, // Entry
+    pub SurfaceInteraction<'a>, // Exit
+);
+
+/////////////////////////////////////////////////////////////////////
+//// A closed object with an inside, which can give every span of the
+//// (whole, infinite) line through o along d that lies inside it.
+//// Spans are sorted and don't overlap; the ones behind the origin
+//// are needed too so that CSG works when starting inside something
+/////////////////////////////////////////////////////////////////////
+pub trait Solid: Object {
+    fn intervals(&self, ray: Ray) -> Vec<Span<'_>>;
+}
+
+pub fn nearest<'a>(spans: &[Span<'a>]) -> Option<SurfaceInteraction<'a>> {
+    //// The first boundary in front of the ray origin
+    for span in spans {
+        if span.0.t > 0. {
+            return Some(span.0.clone());
+        }
+        if span.1.t > 0. {
+            return Some(span.1.clone());
+        }
+    }
+    None
 }
 
 /////////////
 //// A Sphere
 /////////////
-/*pub struct Sphere {
-    pub centre: Vec3,
-    pub radius: f32,
-    pub texture: fn (Vec3) -> Colour,
-    pub material: Material,
-}*/
 pub struct Sphere {
     pub centre: Vec3,
     pub radius: f32,
     pub material: Material,
-    pub texture: fn(Vec3) -> Colour,
+    pub texture: Arc<dyn Texture>,
 }
 
 impl Object for Sphere {
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit> {
-        let a = dot(d, d);
-        let to = o - self.centre;
-        let b = 2. * dot(d, to);
-        let c = dot(to, to) - self.radius*self.radius;
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        nearest(&self.intervals(ray))
+    }
 
-        let d2 = b*b-4.*a*c;
+    fn sample_surface(&self, (a, b): (f32, f32)) -> Option<SurfaceSample<'_>> {
+        // Even in height is even in area (Archimedes' hat-box theorem)
+        let y = 1. - 2. * a;
+        let across = (1. - y*y).max(0.).sqrt();
+        let phi = 2. * PI * b;
+        let normal = Vec3::new(across * phi.cos(), y, across * phi.sin());
+
+        Some(SurfaceSample {
+            point: self.centre + normal * self.radius,
+            normal,
+            uv: sphere_map(normal).0,
+            area: 4. * PI * self.radius * self.radius,
+            material: &self.material,
+        })
+    }
+}
 
-        if d2 < 0. {
-            return None;
-        }
-        let mut res = (-b - d2.sqrt())/(2.*a);
-        if res < 0. {
-            let res2 = (-b + d2.sqrt())/(2.*a);
-            if res2 < 0. {
-                return None;
-            }
-            res = res2
-            //return Some(Hit(res2, self.material, (self.texture)(o+res2*d - self.centre), self.normal(o+res2*d)));
-        }
+impl Solid for Sphere {
+    fn intervals(&self, ray: Ray) -> Vec<Span<'_>> {
+        sphere_spans(self.centre, self.radius, &self.material, &*self.texture, ray)
+    }
+}
 
-        let hitpos = o + res * d;
+fn sphere_spans<'a>(centre: Vec3, radius: f32, material: &'a Material, texture: &dyn Texture, ray: Ray) -> Vec<Span<'a>> {
+    //// Where a ray goes through a sphere; shared by the moving ones
+    let (o, d) = (ray.o, ray.d);
+    let a = dot(d, d);
+    let to = o - centre;
+    let b = 2. * dot(d, to);
+    let c = dot(to, to) - radius*radius;
 
-        Some(Hit(res, hitpos, self.material, (self.texture)(hitpos - self.centre), self.normal(hitpos)))
+    let d2 = b*b-4.*a*c;
+
+    if d2 <= 0. {
+        return vec![];
     }
 
+    let hit = |t: f32| {
+        let p = ray.at(t);
+        let mapping = sphere_map(p - centre);
+        SurfaceInteraction::new(ray, t, (p - centre)/radius, mapping, material, texture.value(mapping.0, p))
+    };
+
+    vec![Span(hit((-b - d2.sqrt())/(2.*a)), hit((-b + d2.sqrt())/(2.*a)))]
+}
+
+fn sphere_map(p: Vec3) -> ((f32, f32), Vec3, Vec3) {
+    //// Longitude and latitude around the y axis of a point relative to
+    //// the centre of a sphere, and how the point moves with each
+    let r = p.length();
+    let across = (p.x()*p.x() + p.z()*p.z()).sqrt();
+    let uv = (0.5 + p.z().atan2(p.x()) / (2. * PI), (p.y() / r).clamp(-1., 1.).acos() / PI);
+
+    let dpdu = 2. * PI * Vec3::new(-p.z(), 0., p.x());
+    let dpdv = if across > 0. {
+        PI * Vec3::new(p.y() * p.x() / across, -across, p.y() * p.z() / across)
+    } else {
+        // At the poles; any direction down will do
+        PI * r * Vec3::new(1., 0., 0.)
+    };
+
+    (uv, dpdu, dpdv)
+}
+
+/////////////////////////////////////////////////////////
+//// A sphere moving in a straight line, from centre0 at
+//// time0 to centre1 at time1 (and carrying on either side)
+/////////////////////////////////////////////////////////
+pub struct MovingSphere {
+    pub centre0: Vec3,
+    pub centre1: Vec3,
+    pub time0: f32,
+    pub time1: f32,
+    pub radius: f32,
+    pub material: Material,
+    pub texture: Arc<dyn Texture>,
+}
+
+impl MovingSphere {
+    fn centre(&self, time: f32) -> Vec3 {
+        //// Where the sphere is at some time
+        let along = (time - self.time0) / (self.time1 - self.time0);
+        self.centre0 + along * (self.centre1 - self.centre0)
+    }
 }
 
-impl Sphere {
-    fn normal(&self, p: Vec3) -> Vec3 {
-        (p - self.centre)/self.radius
+impl Object for MovingSphere {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        nearest(&self.intervals(ray))
+    }
+}
+
+impl Solid for MovingSphere {
+    fn intervals(&self, ray: Ray) -> Vec<Span<'_>> {
+        sphere_spans(self.centre(ray.time), self.radius, &self.material, &*self.texture, ray)
     }
 }
 
 impl Object for Vec<Box<dyn Object>> {
     //// This is clever; make a vector of boxed Objects an Object
     //// so that they can trivially be intersected
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit> {
-        let mut hit: Option<Hit> = None;
-
-        for object in self.iter() {
-            if let Some(candidate_hit) = object.intersect(o, d) {
-                match hit {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        let mut hit: Option<SurfaceInteraction> = None;
+
+        for (id, object) in self.iter().enumerate() {
+            count_work(1);
+            if let Some(mut candidate_hit) = object.intersect(ray) {
+                candidate_hit.id = id;
+                match &hit {
                     None => hit = Some(candidate_hit),
-                    Some(prev) => if candidate_hit.0 < prev.0 {
+                    Some(prev) => if candidate_hit.t < prev.t {
                         hit = Some(candidate_hit);
                     }
                 }
@@ -95,18 +268,19 @@ impl Object for Vec<Box<dyn Object>> {
     }
 }
 
-////////////////////////////////////////////////////////
-//// A general plane (currently without texture support)
-////////////////////////////////////////////////////////
+//////////////////////////////////////////////////////////
+//// A general plane; uv are distances along it from point
+//////////////////////////////////////////////////////////
 pub struct Plane {
     pub point: Vec3,
     pub normal: Vec3,
-    pub colour: Colour,
+    pub texture: Arc<dyn Texture>,
     pub material: Material,
 }
 
 impl Object for Plane {
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit> {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        let (o, d) = (ray.o, ray.d);
         let angle = dot(d, self.normal);
         
         if angle.abs() < 0.001 {
@@ -119,23 +293,32 @@ impl Object for Plane {
             return None
         }
 
-        Some(Hit(t, o+t*d, self.material, self.colour, self.normal))
+        let p = o + t*d;
+        let (tangent, bitangent) = basis(self.normal);
+        let uv = (dot(p - self.point, tangent), dot(p - self.point, bitangent));
+
+        Some(SurfaceInteraction::new(ray, t, self.normal, (uv, tangent, bitangent), &self.material, self.texture.value(uv, p)))
     }
 }
 
+#[allow(non_camel_case_types)]
 pub struct Rect_XY {
     pub x0: f32,
     pub x1: f32,
     pub y0: f32,
     pub y1: f32,
     pub k: f32,
-    pub colour: Colour,
+    pub texture: Arc<dyn Texture>,
     pub material: Material,
 }
 
 impl Object for Rect_XY {
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit> {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        let (o, d) = (ray.o, ray.d);
         let t = (self.k - o.z()) / d.z();
+        if t.is_nan() || t <= 0. {
+            return None;
+        }
         let x = o.x() + t * d.x();
         let y = o.y() + t * d.y();
 
@@ -143,7 +326,24 @@ impl Object for Rect_XY {
             return None;
         }
 
-        Some(Hit(t, o + t * d, self.material, self.colour, Vec3::new(0., 0., -1.)))
+        let uv = ((x - self.x0) / (self.x1 - self.x0), (y - self.y0) / (self.y1 - self.y0));
+        let p = o + t * d;
+
+        Some(SurfaceInteraction::new(
+            ray, t, Vec3::new(0., 0., -1.),
+            (uv, Vec3::new(self.x1 - self.x0, 0., 0.), Vec3::new(0., self.y1 - self.y0, 0.)),
+            &self.material, self.texture.value(uv, p),
+        ))
+    }
+
+    fn sample_surface(&self, (a, b): (f32, f32)) -> Option<SurfaceSample<'_>> {
+        Some(SurfaceSample {
+            point: Vec3::new(self.x0 + a * (self.x1 - self.x0), self.y0 + b * (self.y1 - self.y0), self.k),
+            normal: Vec3::new(0., 0., -1.),
+            uv: (a, b),
+            area: ((self.x1 - self.x0) * (self.y1 - self.y0)).abs(),
+            material: &self.material,
+        })
     }
 }
 
@@ -151,17 +351,17 @@ pub struct Triangle {
     pub v0: Vec3,
     pub v1: Vec3,
     pub v2: Vec3,
-    pub colour: Colour,
+    pub texture: Arc<dyn Texture>, // uv are the barycentric weights of v1 and v2
     pub material: Material,
 }
 
 impl Object for Triangle {
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit> {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        let (o, d) = (ray.o, ray.d);
         let v0v1 = self.v1 - self.v0;
         let v0v2 = self.v2 - self.v0;
 
         let norm = cross(v0v1, v0v2);
-        let area2 = norm.length();
 
         let norm_direction = dot(norm, d);
 
@@ -170,9 +370,9 @@ impl Object for Triangle {
         }
 
         let d2 = dot(norm, self.v0);
-        let t: f32 = (dot(norm, o) + d2)/norm_direction;
+        let t: f32 = (d2 - dot(norm, o))/norm_direction;
 
-        if t < 0. {
+        if t <= 0. {
             return None;
         }
 
@@ -186,113 +386,463 @@ impl Object for Triangle {
 
         let edge1 = self.v2 - self.v1;
         let vp1 = p - self.v1;
-        if dot(norm, cross(edge1, vp1)) < 0. {
+        let area0 = dot(norm, cross(edge1, vp1));
+        if area0 < 0. {
             return None;
         }
 
         let edge2 = self.v0 - self.v2;
         let vp2 = p - self.v2;
-        if dot(norm, cross(edge2, vp2)) < 0. {
+        let area1 = dot(norm, cross(edge2, vp2));
+        if area1 < 0. {
             return None;
         }
 
+        // Barycentric coordinates, from the areas of the triangles opposite each vertex
+        let area = dot(norm, norm);
+        let (w0, w1) = (area0 / area, area1 / area);
+        let uv = (w1, 1. - w0 - w1);
 
-        Some(Hit(t, p, self.material, self.colour, norm))
+        Some(SurfaceInteraction::new(ray, t, norm, (uv, v0v1, v0v2), &self.material, self.texture.value(uv, p)))
+    }
+
+    fn sample_surface(&self, (a, b): (f32, f32)) -> Option<SurfaceSample<'_>> {
+        // Folding the square in half along its diagonal gives the triangle
+        let (u, v) = if a + b > 1. {(1. - a, 1. - b)} else {(a, b)};
+        let norm = cross(self.v1 - self.v0, self.v2 - self.v0);
+
+        Some(SurfaceSample {
+            point: self.v0 + (self.v1 - self.v0) * u + (self.v2 - self.v0) * v,
+            normal: norm.normalise(),
+            uv: (u, v),
+            area: norm.length() / 2.,
+            material: &self.material,
+        })
     }
 }
 
 pub struct AABB {
     pub min: Vec3,
     pub max: Vec3,
-    pub colour: Colour,
+    pub texture: Arc<dyn Texture>, // uv go across each face
     pub material: Material,
 }
 
 
-impl Object for AABB {
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit> {
-        let mut norm = Vec3::new(0., 0., 0.);
-        let mut t_near = -INFINITY;
-        let mut t_far = INFINITY;
-        let mut t_1 = Vec3::new(0., 0., 0.);
-        let mut t_2 = Vec3::new(0., 0., 0.);
+impl AABB {
+    fn slabs(&self, ray: Ray) -> Option<Span<'_>> {
+        //// Clip the line against each pair of planes in turn,
+        //// keeping track of which axis the near and far hits are on
+        let (o, d) = (ray.o, ray.d);
+        let mut t_near = -f32::INFINITY;
+        let mut t_far = f32::INFINITY;
+        let mut near_norm = Vec3::new(0., 0., 0.);
+        let mut far_norm = Vec3::new(0., 0., 0.);
 
         for i in 0..3 {
             if d.value[i] == 0. {
+                // Ray parallel to these planes; either always between them or never
                 if o.value[i] < self.min.value[i] || o.value[i] > self.max.value[i] {
                     return None;
                 }
+                continue;
+            }
+
+            let mut t_1 = (self.min.value[i] - o.value[i]) / d.value[i];
+            let mut t_2 = (self.max.value[i] - o.value[i]) / d.value[i];
+            let mut norm = Vec3::new(0., 0., 0.);
+            norm.value[i] = -1.;
+
+            if t_1 > t_2 {
+                std::mem::swap(&mut t_1, &mut t_2);
+                norm = -norm;
+            }
+
+            if t_1 > t_near {
+                t_near = t_1;
+                near_norm = norm;
+            }
+
+            if t_2 < t_far {
+                t_far = t_2;
+                far_norm = -norm;
+            }
+
+            if t_near > t_far {
+                return None;
+            }
+        }
+
+        Some(Span(self.hit(ray, t_near, near_norm), self.hit(ray, t_far, far_norm)))
+    }
+
+    fn hit(&self, ray: Ray, t: f32, norm: Vec3) -> SurfaceInteraction<'_> {
+        let p = ray.at(t);
+        // The face is across whichever axis the normal is along
+        let axis = (0..3).find(|&i| norm.value[i] != 0.).unwrap_or(0);
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let size = self.max - self.min;
+        let uv = ((p.value[a] - self.min.value[a]) / size.value[a], (p.value[b] - self.min.value[b]) / size.value[b]);
+
+        let mut dpdu = Vec3::new(0., 0., 0.);
+        let mut dpdv = Vec3::new(0., 0., 0.);
+        dpdu.value[a] = size.value[a];
+        dpdv.value[b] = size.value[b];
+
+        SurfaceInteraction::new(ray, t, norm, (uv, dpdu, dpdv), &self.material, self.texture.value(uv, p))
+    }
+}
+
+impl Object for AABB {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        nearest(&self.intervals(ray))
+    }
+}
+
+impl Solid for AABB {
+    fn intervals(&self, ray: Ray) -> Vec<Span<'_>> {
+        self.slabs(ray).into_iter().collect()
+    }
+}
+
+////////////////////////////////////////////////////////
+//// A capped cylinder, standing on `base` along `axis`
+//// (which should be normalised) up to `height`
+////////////////////////////////////////////////////////
+pub struct Cylinder {
+    pub base: Vec3,
+    pub axis: Vec3,
+    pub radius: f32,
+    pub height: f32,
+    pub texture: Arc<dyn Texture>, // u around, v up the side; caps are mapped flat
+    pub material: Material,
+}
+
+impl Cylinder {
+    fn span(&self, ray: Ray) -> Option<Span<'_>> {
+        let (o, d) = (ray.o, ray.d);
+        let to = o - self.base;
+
+        // Split the ray into parts along and across the axis
+        let o_along = dot(to, self.axis);
+        let d_along = dot(d, self.axis);
+        let o_across = to - o_along * self.axis;
+        let d_across = d - d_along * self.axis;
+
+        // Between the two caps
+        let (mut t_near, mut t_far, mut near_norm, mut far_norm);
+        if d_along == 0. {
+            if o_along < 0. || o_along > self.height {
+                return None;
+            }
+            t_near = -f32::INFINITY;
+            t_far = f32::INFINITY;
+            near_norm = -self.axis;
+            far_norm = self.axis;
+        } else {
+            let t_base = -o_along / d_along;
+            let t_top = (self.height - o_along) / d_along;
+            if t_base < t_top {
+                t_near = t_base; near_norm = -self.axis;
+                t_far = t_top; far_norm = self.axis;
             } else {
-                // Ray not parallel
-                t_1.value[i] = (self.min.value[i] - o.value[i]) / d.value[i];
-                t_2.value[i] = (self.max.value[i] - o.value[i]) / d.value[i];
+                t_near = t_top; near_norm = self.axis;
+                t_far = t_base; far_norm = -self.axis;
+            }
+        }
 
-                if t_1.value[i]>t_2.value[i] {
-                    let (t_2, t_1) = (t_1, t_2);
-                }
+        // Inside the infinite tube
+        let a = dot(d_across, d_across);
+        let b = 2. * dot(d_across, o_across);
+        let c = dot(o_across, o_across) - self.radius*self.radius;
 
-                if t_1.value[i] > t_near {
-                    t_near = t_1.value[i];
-                }
+        if a == 0. {
+            // Running parallel to the axis
+            if c > 0. {
+                return None;
+            }
+        } else {
+            let d2 = b*b - 4.*a*c;
+            if d2 <= 0. {
+                return None;
+            }
+            let t_1 = (-b - d2.sqrt())/(2.*a);
+            let t_2 = (-b + d2.sqrt())/(2.*a);
 
-                if t_2.value[i] < t_far {
-                    t_far = t_2.value[i];
-                }
+            if t_1 > t_near {
+                t_near = t_1;
+                near_norm = (o_across + t_1 * d_across) / self.radius;
+            }
+            if t_2 < t_far {
+                t_far = t_2;
+                far_norm = (o_across + t_2 * d_across) / self.radius;
+            }
+        }
+
+        if t_near > t_far {
+            return None;
+        }
+
+        Some(Span(self.hit(ray, t_near, near_norm), self.hit(ray, t_far, far_norm)))
+    }
+
+    fn hit(&self, ray: Ray, t: f32, norm: Vec3) -> SurfaceInteraction<'_> {
+        let p = ray.at(t);
+        let to = p - self.base;
+        let along = dot(to, self.axis);
+        let across = to - along * self.axis;
+        let (side, up) = basis(self.axis);
+
+        let (uv, dpdu, dpdv) = if dot(norm, self.axis).abs() > 0.5 {
+            // On a cap
+            (
+                (0.5 + 0.5 * dot(across, side) / self.radius, 0.5 + 0.5 * dot(across, up) / self.radius),
+                side * 2. * self.radius,
+                up * 2. * self.radius,
+            )
+        } else {
+            (
+                (0.5 + dot(across, up).atan2(dot(across, side)) / (2. * PI), along / self.height),
+                2. * PI * cross(self.axis, across),
+                self.axis * self.height,
+            )
+        };
+
+        SurfaceInteraction::new(ray, t, norm, (uv, dpdu, dpdv), &self.material, self.texture.value(uv, p))
+    }
+}
+
+impl Object for Cylinder {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        nearest(&self.intervals(ray))
+    }
+}
 
-                if t_near > t_far || t_far < 0. {
+impl Solid for Cylinder {
+    fn intervals(&self, ray: Ray) -> Vec<Span<'_>> {
+        self.span(ray).into_iter().collect()
+    }
+}
+
+///////////////////////////////////////////////////////////////
+//// Region a marched object is known to lie within, around its
+//// centre (in world units, i.e. after scaling). Marching only
+//// happens inside it, which saves a lot of steps
+///////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub enum Bound {
+    Unbounded,
+    Sphere(f32), // Radius
+    Box(Vec3), // Half size
+}
+
+impl Bound {
+    fn clip(self, o: Vec3, d: Vec3, max: f32) -> Option<(f32, f32)> {
+        //// The range of t along the (normalised) ray inside the bound
+        let (near, far) = match self {
+            Bound::Unbounded => (0., max),
+            Bound::Sphere(r) => {
+                let b = dot(d, o);
+                let c = dot(o, o) - r*r;
+                let d2 = b*b - c;
+                if d2 <= 0. {
                     return None;
                 }
-            }
-        }
+                (-b - d2.sqrt(), -b + d2.sqrt())
+            },
+            Bound::Box(half) => {
+                let mut near = -f32::INFINITY;
+                let mut far = f32::INFINITY;
+                for i in 0..3 {
+                    if d.value[i] == 0. {
+                        if o.value[i].abs() > half.value[i] {
+                            return None;
+                        }
+                        continue;
+                    }
+                    let t_1 = (-half.value[i] - o.value[i]) / d.value[i];
+                    let t_2 = (half.value[i] - o.value[i]) / d.value[i];
+                    near = near.max(t_1.min(t_2));
+                    far = far.min(t_1.max(t_2));
+                }
+                (near, far)
+            },
+        };
 
-        let t = if t_near > 0. {t_near} else {t_far};
-        Some(Hit(t, o + t * d, self.material, self.colour, Vec3::new(1., 1., 1.)))
+        let (near, far) = (near.max(0.), far.min(max));
+        if near > far {
+            return None;
+        }
+        Some((near, far))
     }
 }
 
-const MARCHDEPTH: u32 = 100;
-const EPSILON: f32 = 0.001;
-const MARCHMAX: f32 = 1_000.;
+///////////////////////////////////////
+//// How a March object does its steps
+///////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct MarchSettings {
+    pub steps: u32, // Most steps before giving up
+    pub epsilon: f32, // How close counts as a hit
+    pub max_distance: f32, // How far to march when unbounded
+    // Over-relaxation; steps are this much longer than the distance (1 to 2),
+    // falling back to normal steps whenever it overshoots
+    pub relaxation: f32,
+    // Most the distance function changes per unit moved; bigger than 1 for
+    // fields that aren't true distances (twisting, bending, fractals...)
+    pub lipschitz: f32,
+}
+
+impl Default for MarchSettings {
+    fn default() -> MarchSettings {
+        MarchSettings {
+            steps: 100,
+            epsilon: 0.001,
+            max_distance: 1_000.,
+            relaxation: 1.,
+            lipschitz: 1.,
+        }
+    }
+}
 
 pub struct March {
     pub centre: Vec3,
     pub scale: f32,
-    pub dist: fn(Vec3) -> f32,
+    pub dist: Sdf,
+    pub bound: Bound,
+    pub settings: MarchSettings,
     pub material: Material,
-    pub colour: Colour,
+    pub texture: Arc<dyn Texture>, // uv map around the centre like a sphere
 }
 
 impl March {
+    fn distance(&self, p: Vec3) -> f32 {
+        //// Distance to the (moved and scaled) field, safe to step along
+        self.dist.distance((p - self.centre) / self.scale) * self.scale / self.settings.lipschitz
+    }
+
     pub fn normal(&self, p: Vec3) -> Vec3 {
-        let x = Vec3::new(EPSILON, 0., 0.);
-        let y = Vec3::new(0., EPSILON, 0.);
-        let z = Vec3::new(0., 0., EPSILON);
+        let e = self.settings.epsilon;
+        let x = Vec3::new(e, 0., 0.);
+        let y = Vec3::new(0., e, 0.);
+        let z = Vec3::new(0., 0., e);
 
         return Vec3::new(
-            ((self.dist)(p + x - self.centre) - (self.dist)(p - x - self.centre)) / (2. * EPSILON),
-            ((self.dist)(p + y - self.centre) - (self.dist)(p - y - self.centre)) / (2. * EPSILON),
-            ((self.dist)(p + z - self.centre) - (self.dist)(p - z - self.centre)) / (2. * EPSILON),
-        )
+            self.distance(p + x) - self.distance(p - x),
+            self.distance(p + y) - self.distance(p - y),
+            self.distance(p + z) - self.distance(p - z),
+        ).normalise()
     }
 }
 
 impl Object for March {
-    fn intersect(&self, o: Vec3, d: Vec3) -> Option<Hit> {
-        let mut p = o;
-        let mut dist: f32;
-
-        for _ in 0..MARCHDEPTH {
-            dist = (self.dist)(p - self.centre);
-            p += dist * d;
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        let (o, d) = (ray.o, ray.d);
+        // March along a normalised direction, so steps are in world units
+        let length = d.length();
+        let d = d / length;
+
+        let (near, far) = self.bound.clip(o - self.centre, d, self.settings.max_distance)?;
+
+        let mut t = near;
+        // Rays starting inside (refraction) march towards the surface from the other side
+        let sign = self.distance(o + t * d).signum();
+
+        let mut relaxation = self.settings.relaxation;
+        let mut prev_t = t;
+        let mut prev_dist = 0.;
+
+        for _ in 0..self.settings.steps {
+            count_work(1);
+            let dist = sign * self.distance(o + t * d);
+
+            // Relaxed steps are only safe while the spheres of the two steps overlap;
+            // if they don't (or it went straight through), go back and step normally from here on
+            if relaxation > 1. && (dist < 0. || dist + prev_dist < t - prev_t) {
+                t = prev_t + prev_dist;
+                relaxation = 1.;
+                continue;
+            }
 
-            if dist < EPSILON {
-                return Some(Hit(dist, p, self.material, self.colour, self.normal(p - self.centre)));
+            if dist < self.settings.epsilon {
+                let p = o + t * d;
+                let mapping = sphere_map(p - self.centre);
+                let mut colour = self.texture.value(mapping.0, p);
+                if let Some(trap) = self.dist.colour((p - self.centre) / self.scale) {
+                    colour = colour * trap;
+                }
+                return Some(SurfaceInteraction::new(ray, t / length, self.normal(p), mapping, &self.material, colour));
             }
 
-            if dist > MARCHMAX {
-                return None;
+            prev_t = t;
+            prev_dist = dist;
+            t += relaxation * dist;
+
+            if t > far {
+                if relaxation == 1. {
+                    return None;
+                }
+                // It might have only left by overshooting, so check with a normal step
+                t = prev_t + prev_dist;
+                relaxation = 1.;
             }
         }
         None
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marched(scale: f32, bound: Bound) -> March {
+        //// A ball of radius 0.8 at (1, 2, 3), as a unit sphere scaled down
+        March {
+            centre: Vec3::new(1., 2., 3.),
+            scale,
+            dist: Sdf::Sphere(0.8 / scale),
+            bound,
+            settings: MarchSettings {epsilon: 1e-5, ..MarchSettings::default()},
+            material: LAMBERT,
+            texture: Arc::new(Colour::white()),
+        }
+    }
+
+    fn ball() -> Sphere {
+        Sphere {
+            centre: Vec3::new(1., 2., 3.),
+            radius: 0.8,
+            texture: Arc::new(Colour::white()),
+            material: LAMBERT,
+        }
+    }
+
+    #[test]
+    fn march_hits_where_the_sphere_does() {
+        //// Directions that aren't normalised give t in multiples of them, like every other shape
+        let rays = [
+            Ray::new(Vec3::new(1., 2., -2.), Vec3::new(0., 0., 3.), 0.),
+            Ray::new(Vec3::new(-4., 0., 1.), Vec3::new(2.5, 1.05, 1.1), 0.),
+            Ray::new(Vec3::new(1.3, 2.2, 10.), Vec3::new(0., 0., -0.5), 0.),
+        ];
+        for bound in [Bound::Sphere(1.), Bound::Box(Vec3::new(1., 1., 1.)), Bound::Unbounded] {
+            let march = marched(0.25, bound);
+            for ray in rays {
+                let expected = ball().intersect(ray).unwrap().t;
+                let t = march.intersect(ray).unwrap().t;
+                assert!((t - expected).abs() < 1e-3, "{} rather than {}", t, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn march_misses_outside_its_bound() {
+        //// This ray goes through the ball, but not the box it's said to be in
+        let ray = Ray::new(Vec3::new(1.5, 2., -2.), Vec3::new(0., 0., 2.), 0.);
+        assert!(ball().intersect(ray).is_some());
+        assert!(marched(0.25, Bound::Box(Vec3::new(0.2, 0.2, 0.2))).intersect(ray).is_none());
+
+        let away = Ray::new(Vec3::new(1., 2., -2.), Vec3::new(0., 0., -1.), 0.);
+        assert!(marched(0.25, Bound::Sphere(1.)).intersect(away).is_none());
+    }
+}
@@ -0,0 +1,22 @@
+use super::vector::*;
+
+//////////////////////////////////////////////////////////////
+//// A ray, fired at some moment while the shutter is open so
+//// that anything moving can be found where it was at the time
+//////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct Ray {
+    pub o: Vec3, // Origin
+    pub d: Vec3, // Direction
+    pub time: f32,
+}
+
+impl Ray {
+    pub fn new(o: Vec3, d: Vec3, time: f32) -> Ray {
+        Ray {o, d, time}
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.o + t * self.d
+    }
+}
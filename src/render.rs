@@ -1,94 +1,329 @@
 use image::{ImageBuffer, RgbImage};
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
 use super::vector::*;
 use super::colour::*;
 use super::primitive::*;
-use super::material::random_float;
+use super::ray::*;
+use super::scene::*;
+use super::sampler::*;
+use super::film::*;
+use super::aov::*;
+use super::denoise::*;
+use super::integrator::*;
 
 
 //texture, material (?)
 
-//// Define a scene, with a background and the shapes in it
-/*pub struct Scene (
-    pub dyn Object,
-    pub fn(Vec3) -> Colour,
-);*/
 
 
-fn random_unit_disc(seed: &mut u32) -> Vec3 {
-    let mut p = 2. * Vec3::new(random_float(seed), random_float(seed), 0.) - Vec3::new(1., 1., 0.);
+fn directions(looking: Vec3, global_up: Vec3) -> (Vec3, Vec3) {
+    // Convert (unit) vectors for viewing direction and global up into orthonormal basis for camera
+    // 'local' up points approx towards global up but perp to looking
+    // Only returns the unknown vectors; program already has looking
+    let side = cross(global_up, looking).normalise();
+    return (side, cross(looking, side));
+}
+
 
-    while dot(p, p) >= 1. {
-        p = 2. * Vec3::new(random_float(seed), random_float(seed), 0.) - Vec3::new(1., 1., 0.);
+//////////////////////////////////
+//// How a picture should be made
+//////////////////////////////////
+pub struct Settings {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub sampler: Box<dyn Sampler>,
+    pub filter: Filter,
+    pub threads: usize, // Rows are shared out between these
+    pub filename: String,
+    pub adaptive: Option<Adaptive>, // Otherwise every pixel gets `samples`
+    pub heatmap: Option<String>, // Where to save how many samples each pixel took
+    pub aovs: Vec<Aov>, // Extra pictures to save, named after filename
+    pub denoiser: Option<Denoiser>,
+    pub integrator: Box<dyn Integrator>,
+}
+
+impl Settings {
+    fn want_aovs(&self) -> bool {
+        //// The denoiser needs the AOVs even if none are being saved
+        !self.aovs.is_empty() || self.denoiser.is_some()
     }
+}
 
-    p
+/////////////////////////////////////////////////////////////////////////
+//// Keep sampling each pixel until it's settled down; until the standard
+//// error of its brightness is less than `threshold` of the brightness
+//// itself, within the bounds on how many samples it can have
+/////////////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct Adaptive {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f32,
 }
 
+////////////////////////////////////////////////////////////////
+//// The running mean and variance of some values (Welford's way,
+//// which doesn't lose precision as they're added up)
+////////////////////////////////////////////////////////////////
+#[derive(Default)]
+struct Variance {
+    count: u32,
+    mean: f32,
+    m2: f32, // Sum of squared differences from the mean
+}
 
-fn trace<T: Object>(o: Vec3, d: Vec3, scene: &T, depth: u32, mut seed: &mut u32) -> Colour {
-    //// Raytrace a whole scene
-    if depth == 0 {
-        // Return white if the raytracing depth is reached
-        // Originally this returned error red; now it returns white
-        return Colour::white();
+impl Variance {
+    fn add(&mut self, x: f32) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (x - self.mean);
     }
 
-    let hit = scene.intersect(o, d);
+    fn variance_of_mean(&self) -> f32 {
+        if self.count < 2 {
+            return 0.;
+        }
+        self.m2 / (self.count - 1) as f32 / self.count as f32
+    }
 
-    match hit {
-        None => {return bg_colour(d)},
-        Some(Hit(dist, hitpos, mat, col, norm)) => {
+    fn relative_error(&self) -> f32 {
+        //// Standard error of the mean, compared to the mean (with a little
+        //// added so black pixels don't need to be exactly black)
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        self.variance_of_mean().sqrt() / (self.mean + 0.01)
+    }
+}
 
-            // Get info about the shape & render or something
-            //let mut hitpos = o + d * dist;
+// How a pixel's sampling went
+#[derive(Copy, Clone)]
+struct PixelStats {
+    samples: u32,
+    variance: f32, // Of the pixel's brightness (the mean of its samples)
+}
 
-            // New direction
-            let newd = (mat.brdf)(norm, d, &mut seed);
+//// Whether something can render
+pub trait Render {
+    fn render(&self, scene: Scene, settings: &mut Settings);
+}
 
-            // Offset the hit position a bit by the normal
-            // If the generated ray is reflected then bump the normal away from the surface,
-            // elif the ray is a refracted ray, bump the normal into the surface
-            //hitpos += norm * 0.01 * if dot(newd, norm) > 0. {1.} else {-1.};
+/////////////////////////////////////////////////////////////////////////
+//// Something that takes pictures; all a camera has to do is say which
+//// ray goes through each point of the picture. Points on the film are
+//// 1 at the top and -1 at the bottom, and the same scale across (so the
+//// sides are at plus and minus the aspect ratio). Lens samples are
+//// random in [0, 1) for cameras with a lens. Cameras that can't see
+//// some of the picture (like a fisheye outside its circle) give None
+/////////////////////////////////////////////////////////////////////////
+pub trait Camera: Sync {
+    fn generate_ray(&self, film_sample: (f32, f32), lens_sample: (f32, f32), time: f32) -> Option<Ray>;
+
+    // When the shutter opens and closes; rays are spread over this time
+    fn shutter(&self) -> (f32, f32);
+
+    // Cameras which light can be traced back into can say where a point shows up
+    // on the film (seen through a point picked on the lens), and how likely they
+    // are to make a ray going some way (per solid angle, or 0 if they never would)
+    fn sample_incoming(&self, _: Vec3, _: (f32, f32), _aspect: f32) -> Option<CameraSample> {
+        None
+    }
 
-            let emittance = col * mat.emission;
+    fn pdf_direction(&self, _: Ray, _aspect: f32) -> Option<f32> {
+        None
+    }
+}
 
-            let albedo = mat.albedo * if mat.cos {clamp(dot(d, -norm))} else {1.};
-            let reflectance = col * trace(hitpos + norm * 0.01 * if dot(newd, norm) > 0. {1.} else {-1.}, newd, scene, depth - 1, &mut seed) * albedo;
+////////////////////////////////////////////////////////////////////////
+//// Where a point shows up on the film, and how much light from it
+//// counts there; importance is to a camera what radiance is to a light
+////////////////////////////////////////////////////////////////////////
+pub struct CameraSample {
+    pub film: (f32, f32),
+    pub lens: Vec3, // The point on the lens the light goes in through
+    pub importance: f32,
+    pub pdf: f32, // Of the point on the lens, per solid angle seen from the point in the scene
+}
 
-            return (emittance + reflectance) * (1./(PI * mat.prob));
-        }
+fn pinhole_projection(looking: Vec3, global_up: Vec3, h: f32, aspect: f32, d: Vec3) -> Option<((f32, f32), f32)> {
+    //// Where a direction from a pinhole lands on its film (h away), and the
+    //// cosine of its angle to the middle; the film is the same as generate_ray's
+    let looking = looking.normalise();
+    let (side, up) = directions(looking, global_up);
+    let cos = dot(d, looking);
+    if cos <= 0. {
+        return None;
+    }
+    let (u, v) = (h * dot(d, side) / cos, h * dot(d, up) / cos);
+    if u.abs() > aspect || v.abs() > 1. {
+        return None;
     }
+    Some(((u, v), cos))
+}
 
+fn pinhole_importance(h: f32, aspect: f32, cos: f32) -> f32 {
+    //// Importance along a ray at some angle to the middle, for a film h away. Films
+    //// which are the same size a distance of 1 away see the same, so this is one over
+    //// the area of that film, and over cos^4 (one cos to turn it into radiance, and
+    //// cos^3 for how much of the film a bit of solid angle takes up)
+    let area = 4. * aspect / (h * h);
+    1. / (area * cos.powi(4))
 }
 
+impl<C: Camera> Render for C {
+    fn render(&self, scene: Scene, settings: &mut Settings) {
+        render_image(self, &scene, settings);
+    }
+}
 
-fn directions(looking: Vec3, global_up: Vec3) -> (Vec3, Vec3) {
-    // Convert (unit) vectors for viewing direction and global up into orthonormal basis for camera
-    // 'local' up points approx towards global up but perp to looking
-    // Only returns the unknown vectors; program already has looking
-    let side = cross(global_up, looking).normalise();
-    return (side, cross(looking, side));
+// For cameras picked when the program runs
+impl Render for dyn Camera {
+    fn render(&self, scene: Scene, settings: &mut Settings) {
+        render_image(self, &scene, settings);
+    }
 }
 
+pub fn render_image(camera: &dyn Camera, scene: &Scene, settings: &mut Settings) {
+    //// Render by splatting the rays a camera makes at random points in each pixel
+    //// onto the film. Threads take whichever row is next; since every sample's
+    //// random numbers depend only on where and which it is, and rows go onto
+    //// the film in order, this makes no difference to the picture
+    settings.integrator.preprocess(scene, settings.threads);
+    let settings = &*settings;
+
+    let mut film = Film::new(settings.width, settings.height);
+    let splats = Splats::new(settings.width, settings.height);
+    let context = Context {scene, camera, width: settings.width, height: settings.height, splats: &splats};
+    let next_row = AtomicU32::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut heat = BTreeMap::new();
+    let mut aovs = Aovs::new(settings.width, settings.height);
+
+    thread::scope(|scope| {
+        for _ in 0..settings.threads.max(1) {
+            let mut sampler = settings.sampler.clone_sampler();
+            let (next_row, sender, context) = (&next_row, sender.clone(), &context);
+
+            scope.spawn(move || loop {
+                let y = next_row.fetch_add(1, Ordering::Relaxed);
+                if y >= settings.height {
+                    return;
+                }
+                let mut tile = Tile::new(y, settings.width, settings.height, settings.filter);
+                let (stats, aov_row) = render_row(context, &mut *sampler, y, settings, &mut tile);
+                sender.send((y, tile, stats, aov_row)).unwrap();
+            });
+        }
+        drop(sender);
+
+        // Rows that arrived early wait until the ones above them are in
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        for (y, tile, stats, aov_row) in receiver {
+            heat.insert(y, stats);
+            aovs.set_row(y, aov_row);
+            waiting.insert(y, tile);
+            while let Some(tile) = waiting.remove(&next) {
+                film.merge(tile);
+                next += 1;
+            }
+        }
+    });
 
-fn bg_colour(d: Vec3) -> Colour {
-    //// A function which gives a sky colour based on a direction
+    // Light traced into the camera lands on pixels regardless of how many samples
+    // they took, so it's shared out by how many were taken over the whole picture
+    let total: u64 = heat.values().flatten().map(|stats| stats.samples as u64).sum();
+    film.add_splats(&splats, (settings.width * settings.height) as f32 / total.max(1) as f32);
 
-    // Sun colour
-    let sundirec = Vec3::new(-1., 1., -1.).normalise();
-    let sunlight = Colour::white() * clamp(dot(sundirec, d) + 0.03).powf(300.);
+    if let Some(denoiser) = &settings.denoiser {
+        let variance: Vec<f32> = heat.values().flatten().map(|stats| stats.variance).collect();
+        film.set_pixels(denoiser.denoise(&film.pixels(), &variance, &aovs, settings.threads));
+    }
 
-    // Lerp between blue and white vertically
-    //let val = ((1. + v.y)/2.).powf(1.5);
-    let val = ((1. + d.y())/2.).powf(1.5);
-    let sky = (Colour::new(0.45, 0.68, 0.87) * (1.-val) + Colour::white() *  val) * 0.4;
+    film.image(settings.integrator.tone_mapped()).save(&settings.filename).unwrap();
 
-    return sunlight * 1. + sky;
+    if let Some(filename) = &settings.heatmap {
+        let most = settings.adaptive.map_or(settings.samples, |adaptive| adaptive.max_samples);
+        heatmap(&heat, settings.width, settings.height, most).save(filename).unwrap();
+    }
+    aovs.save(&settings.aovs, &settings.filename);
+    println!("All done");
 }
 
-//// Whether something can render
-pub trait Render {
-    fn render(&self, scene: Vec<Box<dyn Object>>, width: u32, height: u32, samples: u32, filename: String);
+fn render_row(context: &Context, sampler: &mut dyn Sampler, y: u32, settings: &Settings, tile: &mut Tile) -> (Vec<PixelStats>, Vec<AovPixel>) {
+    //// Sample each pixel along a row, giving how that went for each,
+    //// and the AOVs of each pixel if they're wanted
+    let camera = context.camera;
+    let (width, height) = (settings.width as f32, settings.height as f32);
+    let (open, close) = camera.shutter();
+    let (min_samples, max_samples, threshold) = match settings.adaptive {
+        Some(adaptive) => (adaptive.min_samples, adaptive.max_samples, adaptive.threshold),
+        None => (settings.samples, settings.samples, 0.),
+    };
+    let mut stats = vec![];
+    let mut aov_row = vec![];
+
+    for x in 0..settings.width {
+        let mut variance = Variance::default();
+        let mut aov_pixel = AovPixel::default();
+        let mut index = 0;
+
+        while index < max_samples && (index < min_samples || variance.relative_error() > threshold) {
+            sampler.start_pixel_sample((x, y), index);
+            let mut rng = sampler.rng();
+
+            let (jitter_x, jitter_y) = sampler.get_2d();
+            let (px, py) = (x as f32 + jitter_x, y as f32 + jitter_y);
+            let film = ((2. * px - width) / height, (height - 2. * py) / height);
+            let lens = sampler.get_2d();
+            let time = open + sampler.get_1d() * (close - open);
+
+            // Rays that can't be made still count, as black
+            let mut aov = AovSample::default();
+            let want_aov = settings.want_aovs().then_some(&mut aov);
+            let col = match camera.generate_ray(film, lens, time) {
+                Some(ray) => settings.integrator.li(ray, context, sampler, &mut rng, want_aov),
+                None => Colour::black(),
+            };
+            tile.add_sample((px, py), col);
+            aov_pixel.add(&aov);
+            variance.add(col.luminance());
+            index += 1;
+        }
+        stats.push(PixelStats {samples: index, variance: variance.variance_of_mean()});
+        if settings.want_aovs() {
+            aov_row.push(aov_pixel);
+        }
+    }
+
+    (stats, aov_row)
+}
+
+fn heatmap(stats: &BTreeMap<u32, Vec<PixelStats>>, width: u32, height: u32, most: u32) -> RgbImage {
+    //// How many samples each pixel took, from blue (none) through green to red (most)
+    ImageBuffer::from_fn(width, height, |x, y| {
+        heat(stats[&y][x as usize].samples as f32 / most.max(1) as f32).to_raw()
+    })
+}
+
+fn unit_disc((a, b): (f32, f32)) -> (f32, f32) {
+    //// Map a lens sample evenly onto the unit disc (concentric mapping,
+    //// which keeps nearby samples together)
+    let (a, b) = (2. * a - 1., 2. * b - 1.);
+    if a == 0. && b == 0. {
+        return (0., 0.);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4. * b / a)
+    } else {
+        (b, PI / 2. - PI / 4. * a / b)
+    };
+    (r * theta.cos(), r * theta.sin())
 }
 
 //// A pinhole camera
@@ -98,49 +333,43 @@ pub struct SimpleCamera {
     pub position: Vec3,
     pub looking: Vec3,
     pub global_up: Vec3,
+    pub shutter_open: f32, // Rays are spread over the time between these
+    pub shutter_close: f32,
 }
 
-impl Render for SimpleCamera {
-    fn render(&self, scene: Vec<Box<dyn Object>>, width: u32, height: u32, samples: u32, filename: String) {
-        // The resulting image
-        let mut finalimg: RgbImage = ImageBuffer::new(width, height);
+impl Camera for SimpleCamera {
+    fn generate_ray(&self, (u, v): (f32, f32), _: (f32, f32), time: f32) -> Option<Ray> {
         // The other 2 vectors for an orthonormal basis
         let (side, up) = directions(self.looking, self.global_up);
+        let h = 1./(self.fov/2.).tan();
 
-        let mut direction: Vec3;
-        let mut u: f32;
-        let mut v: f32;
+        let direction = (h * self.looking + u * side + v * up).normalise();
+        Some(Ray::new(self.position, direction, time))
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
 
+    fn sample_incoming(&self, p: Vec3, _: (f32, f32), aspect: f32) -> Option<CameraSample> {
         let h = 1./(self.fov/2.).tan();
+        let to = self.position - p;
+        let distance = to.length();
+        let (film, cos) = pinhole_projection(self.looking, self.global_up, h, aspect, -to / distance)?;
+
+        Some(CameraSample {
+            film,
+            lens: self.position,
+            importance: pinhole_importance(h, aspect, cos),
+            pdf: distance * distance / cos,
+        })
+    }
 
-        // The resulting colour at a point
-        let mut col: Colour;
-        // The seed
-        let mut seed: u32 = 4839;
-    
-        for (x, y, pixel) in finalimg.enumerate_pixels_mut() {
-            // Initialise the colour
-            col = Colour::black();
-            
-            for _ in 0..samples {
-                u = ((2.*x as f32)/((width-1) as f32) - 1.)*(width as f32)/(height as f32);
-                v = (-2.*y as f32)/((height-1) as f32) + 1.;
-    
-                // Maybe these constants are off; unknown
-                u += (random_float(&mut seed) - 0.5)/(height as f32 - 1.)*2.;
-                v += (random_float(&mut seed) - 0.5)/(height as f32 - 1.)*2.;
-                // originally h = 3.
-                direction = (h * self.looking + u * side + v * up).normalise();
-    
-                col += trace(self.position, direction, &scene, 4, &mut seed) //* (1./SAMPLES as f32);
-            }
-    
-            *pixel = (col / samples as f32).clamp().to_rgb();
-    
-        }
-    
-    finalimg.save(filename).unwrap();
-    println!("All done");    
+    fn pdf_direction(&self, ray: Ray, aspect: f32) -> Option<f32> {
+        let h = 1./(self.fov/2.).tan();
+        let pdf = pinhole_projection(self.looking, self.global_up, h, aspect, ray.d.normalise())
+            .map_or(0., |(_, cos)| pinhole_importance(h, aspect, cos) * cos);
+        Some(pdf)
     }
 }
 
@@ -152,62 +381,377 @@ pub struct DOFCamera {
     pub global_up: Vec3,
     pub aperture: f32, // Camera aperture
     pub focus: f32, // Focal distance if that's the right word or something
+    pub shutter_open: f32, // Rays are spread over the time between these
+    pub shutter_close: f32,
 }
 
+impl Camera for DOFCamera {
+    fn generate_ray(&self, (u, v): (f32, f32), lens: (f32, f32), time: f32) -> Option<Ray> {
+        let (side, up) = directions(self.looking, self.global_up);
+        let half_height = (self.fov/2.).tan();
 
-impl Render for DOFCamera {
-    fn render(&self, scene: Vec<Box<dyn Object>>, width: u32, height: u32, samples: u32, filename: String) {
-        // The resulting image
-        let mut finalimg: RgbImage = ImageBuffer::new(width, height);
-        // The other 2 vectors for an orthonormal basis
+        // Start somewhere on the aperture, and go through the point that's in focus
+        let (lens_x, lens_y) = unit_disc(lens);
+        let offset = (side * lens_x + up * lens_y) * self.aperture;
+        let direction = ((1./half_height) * self.looking + u * side + v * up).normalise() * self.focus;
+
+        Some(Ray::new(self.position + offset, (direction - offset).normalise(), time))
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+
+    // Rays through the lens are treated as though they were focused on a plane (as a
+    // real thin lens would), rather than on the sphere `focus` from the camera they
+    // actually are, for importance; close enough with an aperture this much smaller
+    fn sample_incoming(&self, p: Vec3, lens: (f32, f32), aspect: f32) -> Option<CameraSample> {
+        let h = 1./(self.fov/2.).tan();
         let (side, up) = directions(self.looking, self.global_up);
+        let (lens_x, lens_y) = unit_disc(lens);
+        let offset = (side * lens_x + up * lens_y) * self.aperture;
+
+        let to = self.position + offset - p;
+        let distance = to.length();
+        let d = -to / distance;
+        let (film, cos) = self.through_focus(offset, d, h, aspect)?;
+        let lens_area = self.lens_area();
+
+        Some(CameraSample {
+            film,
+            lens: self.position + offset,
+            importance: pinhole_importance(h, aspect, cos) / lens_area,
+            pdf: distance * distance / (cos * lens_area),
+        })
+    }
 
-        let mut direction: Vec3;
-        // Positions for direction or something
-        let mut u: f32;
-        let mut v: f32;
+    fn pdf_direction(&self, ray: Ray, aspect: f32) -> Option<f32> {
+        let h = 1./(self.fov/2.).tan();
+        let pdf = self.through_focus(ray.o - self.position, ray.d.normalise(), h, aspect)
+            .map_or(0., |(_, cos)| pinhole_importance(h, aspect, cos) * cos);
+        Some(pdf)
+    }
+}
 
-        let half_height = (self.fov/2.).tan();
-        let half_width = half_height * (width as f32) / (height as f32);
-
-        // The resulting colour at a point
-        let mut col: Colour;
-        // The seed
-        let mut seed: u32 = 4839;
-        
-        // Random variables for aperture
-        let mut rand_disc: Vec3;
-        let mut offset: Vec3;
-        //let horizontal = 2. * 1./half_width * self.aperture * side;
-        //let vertical = 2. * 1./half_height * self.aperture * up;
-
-
-        for (x, y, pixel) in finalimg.enumerate_pixels_mut() {
-            // Initialise the colour
-            col = Colour::black();
-            
-            for _ in 0..samples {
-                u = ((2.*x as f32)/((width-1) as f32) - 1.)*(width as f32)/(height as f32);
-                v = (-2.*y as f32)/((height-1) as f32) + 1.;
-    
-                // Maybe these constants are off; unknown
-                //u += (random_float(&mut seed) - 0.5)/(height as f32 - 1.)*2.;
-                //v += (random_float(&mut seed) - 0.5)/(height as f32 - 1.)*2.;
-
-                
-                // Aperture part:
-                rand_disc = random_unit_disc(&mut seed);
-                offset = (side * rand_disc.x() + up * rand_disc.y()) * self.aperture;
-                direction = ((1./half_height) * self.looking + u * side + v * up).normalise() * self.focus;
-                
-                col += trace(self.position + offset, (direction - offset).normalise(), &scene, 4, &mut seed) //* (1./SAMPLES as f32);
+impl DOFCamera {
+    fn lens_area(&self) -> f32 {
+        //// Pinholes have no area, but their pdfs are all 1
+        if self.aperture > 0. {PI * self.aperture * self.aperture} else {1.}
+    }
+
+    fn through_focus(&self, offset: Vec3, d: Vec3, h: f32, aspect: f32) -> Option<((f32, f32), f32)> {
+        //// Where a ray leaving the lens `offset` from its middle came from on the film,
+        //// by where it crosses the sphere in focus; and the cos of its angle to the middle
+        let cos = dot(d, self.looking.normalise());
+        if cos <= 0. {
+            return None;
+        }
+        let along = dot(offset, d);
+        let t = -along + (along * along - offset.length_squared() + self.focus * self.focus).max(0.).sqrt();
+        let focused = (offset + d * t).normalise();
+        let (film, _) = pinhole_projection(self.looking, self.global_up, h, aspect, focused)?;
+        Some((film, cos))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+//// A camera described like a real one, with world units in metres.
+//// The sensor's height fills the picture's height; `resolution` gives
+//// the picture width that matches the sensor's shape. Things are sharp
+//// on the plane `focus` away, and the aperture is round, or a polygon
+//// when there are 3 or more blades (which is the shape bokeh takes)
+////////////////////////////////////////////////////////////////////////
+pub struct PhysicalCamera {
+    pub focal_length: f32, // mm
+    pub sensor: (f32, f32), // Width and height in mm; 36 by 24 is full frame
+    pub f_number: f32,
+    pub focus: f32,
+    pub blades: u32,
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub global_up: Vec3,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl PhysicalCamera {
+    pub fn resolution(&self, height: u32) -> (u32, u32) {
+        //// The picture size with the same shape as the sensor
+        ((height as f32 * self.sensor.0 / self.sensor.1).round() as u32, height)
+    }
+
+    pub fn autofocus(&mut self, scene: &Scene) {
+        //// Focus on whatever's in the middle of the picture (if anything is)
+        let time = (self.shutter_open + self.shutter_close) / 2.;
+        if let Some(hit) = scene.intersect(Ray::new(self.position, self.looking, time)) {
+            self.focus = hit.t * self.looking.length();
+        }
+    }
+
+    fn aperture(&self, (a, b): (f32, f32)) -> (f32, f32) {
+        //// A point spread evenly over the aperture, with a radius of 1
+        if self.blades < 3 {
+            return unit_disc((a, b));
+        }
+
+        // Pick one of the triangles between the centre and each edge of the polygon (they're all
+        // the same size), then a point in it; the square root spreads them evenly out from the centre
+        let n = self.blades as f32;
+        let edge = (a * n).floor();
+        let (a, b) = (a * n - edge, b);
+        let (start, end) = (2. * PI * edge / n, 2. * PI * (edge + 1.) / n);
+        let r = a.sqrt();
+
+        (
+            r * ((1. - b) * start.cos() + b * end.cos()),
+            r * ((1. - b) * start.sin() + b * end.sin()),
+        )
+    }
+}
+
+impl Camera for PhysicalCamera {
+    fn generate_ray(&self, (u, v): (f32, f32), lens: (f32, f32), time: f32) -> Option<Ray> {
+        let (side, up) = directions(self.looking, self.global_up);
+        let looking = self.looking.normalise();
+
+        // Sizes in metres
+        let focal_length = self.focal_length / 1000.;
+        let half_height = self.sensor.1 / 2000.;
+        let radius = focal_length / (2. * self.f_number);
+
+        // The point on the plane of focus this part of the picture sees,
+        // reached through a random point on the aperture
+        let pinhole = looking + (u * side + v * up) * (half_height / focal_length);
+        let focused = self.position + pinhole * self.focus;
+        let (lens_x, lens_y) = self.aperture(lens);
+        let origin = self.position + (side * lens_x + up * lens_y) * radius;
+
+        Some(Ray::new(origin, (focused - origin).normalise(), time))
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//// An orthographic camera; parallel rays, so things stay the same
+//// size however far away they are. `size` is the height of the
+//// view in world units
+/////////////////////////////////////////////////////////////////////
+pub struct OrthoCamera {
+    pub size: f32,
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub global_up: Vec3,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Camera for OrthoCamera {
+    fn generate_ray(&self, (u, v): (f32, f32), _: (f32, f32), time: f32) -> Option<Ray> {
+        let (side, up) = directions(self.looking, self.global_up);
+        let scale = self.size / 2.;
+        Some(Ray::new(self.position + (u * side + v * up) * scale, self.looking, time))
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+}
+
+////////////////////////////////////////////////////////////////
+//// How a fisheye lens spreads angles out over the image circle
+////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub enum Fisheye {
+    Equidistant, // Distance from the centre goes with the angle
+    Equisolid, // Area goes with the solid angle
+}
+
+/////////////////////////////////////////////////////////////////////
+//// A fisheye camera; a circular image fitting the height of the
+//// picture, with `fov` across it (which can be more than PI)
+/////////////////////////////////////////////////////////////////////
+pub struct FisheyeCamera {
+    pub fov: f32,
+    pub projection: Fisheye,
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub global_up: Vec3,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Camera for FisheyeCamera {
+    fn generate_ray(&self, (u, v): (f32, f32), _: (f32, f32), time: f32) -> Option<Ray> {
+        let (side, up) = directions(self.looking, self.global_up);
+
+        // The image circle has a radius of 1
+        let r = (u*u + v*v).sqrt();
+        if r > 1. {
+            return None;
+        }
+
+        // Angle away from straight ahead
+        let theta = match self.projection {
+            Fisheye::Equidistant => r * self.fov / 2.,
+            Fisheye::Equisolid => 2. * (r * (self.fov / 4.).sin()).asin(),
+        };
+        let (cos_phi, sin_phi) = if r > 0. {(u / r, v / r)} else {(1., 0.)};
+        let direction = theta.cos() * self.looking + theta.sin() * (cos_phi * side + sin_phi * up);
+
+        Some(Ray::new(self.position, direction, time))
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+//// A 360 degree panorama; longitude across and latitude up the image,
+//// with `looking` in the middle. Pictures should be twice as wide as
+//// they are high
+//////////////////////////////////////////////////////////////////////
+pub struct EquirectCamera {
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub global_up: Vec3,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Camera for EquirectCamera {
+    fn generate_ray(&self, (u, v): (f32, f32), _: (f32, f32), time: f32) -> Option<Ray> {
+        let (side, up) = directions(self.looking, self.global_up);
+
+        let longitude = u / 2. * PI;
+        let latitude = v / 2. * PI;
+        let around = longitude.cos() * self.looking + longitude.sin() * side;
+        let direction = latitude.cos() * around + latitude.sin() * up;
+
+        Some(Ray::new(self.position, direction, time))
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////
+//// A cubemap; the six 90 degree views from a point laid out in a grid
+//// three across and two down. The top row is right, left and up, and
+//// the bottom row is down, front and back. Pictures should be 3:2
+///////////////////////////////////////////////////////////////////////
+pub struct CubemapCamera {
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub global_up: Vec3,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Camera for CubemapCamera {
+    fn generate_ray(&self, (u, v): (f32, f32), _: (f32, f32), time: f32) -> Option<Ray> {
+        let (side, up) = directions(self.looking, self.global_up);
+        let looking = self.looking;
+
+        // (Forward, right, up) for each face
+        let faces = [
+            (side, -looking, up),
+            (-side, looking, up),
+            (up, side, -looking),
+            (-up, side, looking),
+            (looking, side, up),
+            (-looking, -side, up),
+        ];
+
+        // Each face is a unit square; find which one, and where across it from -1 to 1
+        let (x, y) = (u + 1.5, 1. - v);
+        let (column, row) = (x.floor().clamp(0., 2.), y.floor().clamp(0., 1.));
+        let (forward, right, face_up) = faces[row as usize * 3 + column as usize];
+        let (face_u, face_v) = (2. * (x - column) - 1., 1. - 2. * (y - row));
+
+        let direction = (forward + face_u * right + face_v * face_up).normalise();
+        Some(Ray::new(self.position, direction, time))
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::*;
+
+    #[test]
+    fn variance_matches_a_direct_computation() {
+        let mut rng = Pcg::new(4839, 0);
+        let values: Vec<f32> = (0..1000).map(|_| 100. + 3. * rng.next_float()).collect();
+        let mut variance = Variance::default();
+        for &x in &values {
+            variance.add(x);
+        }
+
+        let n = values.len() as f64;
+        let mean = values.iter().map(|&x| x as f64).sum::<f64>() / n;
+        let sample_variance = values.iter().map(|&x| (x as f64 - mean).powi(2)).sum::<f64>() / (n - 1.);
+        assert!((variance.mean as f64 - mean).abs() < 1e-4 * mean);
+        assert!((variance.variance_of_mean() as f64 - sample_variance / n).abs() < 1e-3 * sample_variance / n);
+    }
+
+    // Puts the film coordinates the ray was made for in its origin
+    struct Flat;
+
+    impl Camera for Flat {
+        fn generate_ray(&self, (u, v): (f32, f32), _: (f32, f32), time: f32) -> Option<Ray> {
+            Some(Ray::new(Vec3::new(u, v, 0.), Vec3::new(0., 0., 1.), time))
+        }
+
+        fn shutter(&self) -> (f32, f32) {
+            (0., 0.)
+        }
+    }
+
+    // Grey on the left of the picture, and noise on the right
+    struct Halves;
+
+    impl Integrator for Halves {
+        fn li(&self, ray: Ray, _: &Context, _: &mut dyn Sampler, rng: &mut Pcg, _: Option<&mut AovSample>) -> Colour {
+            if ray.o.x() < 0. {
+                Colour::new(0.5, 0.5, 0.5)
+            } else {
+                Colour::white() * rng.next_float()
             }
-    
-            *pixel = (col / samples as f32).clamp().to_rgb();
-    
         }
-    
-    finalimg.save(filename).unwrap();
-    println!("All done");    
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn adaptive_sampling_stops_when_a_pixel_settles() {
+        let settings = Settings {
+            width: 2,
+            height: 1,
+            samples: 0,
+            sampler: Box::new(Independent::new(4839)),
+            filter: Filter::Box {radius: 0.5},
+            threads: 1,
+            filename: String::new(),
+            adaptive: Some(Adaptive {min_samples: 16, max_samples: 256, threshold: 0.001}),
+            heatmap: None,
+            aovs: vec![],
+            denoiser: None,
+            integrator: Box::new(Halves),
+        };
+        let scene = Scene::new(vec![]);
+        let splats = Splats::new(2, 1);
+        let context = Context {scene: &scene, camera: &Flat, width: 2, height: 1, splats: &splats};
+        let mut tile = Tile::new(0, 2, 1, settings.filter);
+
+        let (stats, _) = render_row(&context, &mut *settings.sampler.clone_sampler(), 0, &settings, &mut tile);
+        assert_eq!(stats[0].samples, 16);
+        assert_eq!(stats[1].samples, 256);
+    }
+}
@@ -0,0 +1,74 @@
+/////////////////////////////////////////////////////////////////////////
+//// A PCG random number generator (O'Neill's PCG32, XSH RR). Each stream
+//// is a separate sequence, so generators seeded the same way but on
+//// different streams aren't correlated with each other
+/////////////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct Pcg {
+    state: u64,
+    increment: u64, // Picks the stream; always odd
+}
+
+const MULTIPLIER: u64 = 6364136223846793005;
+
+impl Pcg {
+    pub fn new(seed: u64, stream: u64) -> Pcg {
+        let mut rng = Pcg {state: 0, increment: (stream << 1) | 1};
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(MULTIPLIER).wrapping_add(self.increment);
+
+        // Output a rotated version of the high bits, rotated by the highest ones
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rotation = (old >> 59) as u32;
+        xorshifted.rotate_right(rotation)
+    }
+
+    pub fn next_float(&mut self) -> f32 {
+        //// Random float in [0, 1); only 24 bits fit exactly, and more could round up to 1
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_reference() {
+        //// The first numbers from pcg32-demo, seeded with 42 on stream 54
+        let mut rng = Pcg::new(42, 54);
+        let numbers: Vec<u32> = (0..6).map(|_| rng.next_u32()).collect();
+        assert_eq!(numbers, [0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e]);
+    }
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let (mut a, mut b) = (Pcg::new(4839, 7), Pcg::new(4839, 7));
+        for _ in 0..1000 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+    }
+
+    #[test]
+    fn streams_differ() {
+        let (mut a, mut b) = (Pcg::new(4839, 7), Pcg::new(4839, 8));
+        let same = (0..1000).filter(|_| a.next_u32() == b.next_u32()).count();
+        assert!(same < 2);
+    }
+
+    #[test]
+    fn floats_in_range() {
+        let mut rng = Pcg::new(4839, 0);
+        let floats: Vec<f32> = (0..10000).map(|_| rng.next_float()).collect();
+        assert!(floats.iter().all(|x| (0. ..1.).contains(x)));
+        let mean = floats.iter().sum::<f32>() / floats.len() as f32;
+        assert!((mean - 0.5).abs() < 0.02, "{}", mean);
+    }
+}
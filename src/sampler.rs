@@ -0,0 +1,424 @@
+use super::rng::*;
+
+/////////////////////////////////////////////////////////////////////////
+//// Where the random numbers for each sample come from. Every sample of
+//// a pixel asks for its numbers in the same order (pixel jitter, lens,
+//// time, then each bounce), and the nth number asked for is the nth
+//// dimension; samplers spread each dimension out well across a pixel
+//// Everything is worked out from the global seed, the pixel and the
+//// sample, so pictures come out the same whatever order pixels are
+//// rendered in, and however many threads there are
+/////////////////////////////////////////////////////////////////////////
+pub trait Sampler: Send + Sync {
+    // Start on sample `index` of a pixel, back at the first dimension
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32);
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> (f32, f32);
+
+    // A generator for anything else the current sample needs random numbers for
+    fn rng(&self) -> Pcg;
+
+    // A fresh copy (for another thread)
+    fn clone_sampler(&self) -> Box<dyn Sampler>;
+}
+
+pub fn mix(a: u32, b: u32) -> u32 {
+    //// Scramble two numbers together into a pseudorandom one
+    let mut h = a.wrapping_mul(0x8da6b343) ^ b.wrapping_add(0x9e3779b9).wrapping_mul(0xd8163841);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    h
+}
+
+fn to_float(x: u32) -> f32 {
+    //// The top 24 bits as a float in [0, 1); any more and it could round up to 1
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+fn pixel_seed(global: u32, (x, y): (u32, u32)) -> u32 {
+    mix(mix(x, y), global)
+}
+
+fn sample_rng(seed: u32, index: u32) -> Pcg {
+    //// Each sample gets its own stream
+    Pcg::new(seed as u64, index as u64)
+}
+
+////////////////////////////////////////////////////
+//// Plain independent random numbers (white noise)
+////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct Independent {
+    global: u32,
+    seed: u32,
+    index: u32,
+    rng: Pcg,
+}
+
+impl Independent {
+    pub fn new(global: u32) -> Independent {
+        Independent {global, seed: 0, index: 0, rng: Pcg::new(0, 0)}
+    }
+}
+
+impl Sampler for Independent {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.seed = pixel_seed(self.global, pixel);
+        self.index = index;
+        // A different stream to the one rng() gives out
+        self.rng = Pcg::new(self.seed as u64, index as u64 | 1 << 32);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.next_float()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.rng.next_float(), self.rng.next_float())
+    }
+
+    fn rng(&self) -> Pcg {
+        sample_rng(self.seed, self.index)
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//// Jittered stratification; every dimension is split into as many
+//// strata as there are samples (in a grid, for pairs), and each
+//// sample lands somewhere random in a different one. Which sample
+//// gets which stratum is shuffled separately for each dimension
+/////////////////////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct Stratified {
+    samples: u32,
+    global: u32,
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl Stratified {
+    pub fn new(samples: u32, global: u32) -> Stratified {
+        Stratified {samples: samples.max(1), global, seed: 0, index: 0, dimension: 0}
+    }
+
+    fn next(&mut self) -> u32 {
+        //// A seed for the next dimension of this pixel
+        self.dimension += 1;
+        mix(self.seed, self.dimension)
+    }
+}
+
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    //// Where i goes in a random permutation of 0..l picked by p, without
+    //// having to store it (Kensler, "Correlated Multi-Jittered Sampling")
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        // Anything that lands past the end goes round again
+        if i < l {
+            break;
+        }
+    }
+    (i + p) % l
+}
+
+impl Sampler for Stratified {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.seed = pixel_seed(self.global, pixel);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let seed = self.next();
+        let stratum = permute(self.index % self.samples, self.samples, seed);
+        let jitter = to_float(mix(seed, self.index));
+        (stratum as f32 + jitter) / self.samples as f32
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        // As square a grid as there can be, with at least a cell for each sample
+        let across = (self.samples as f32).sqrt().ceil() as u32;
+        let down = self.samples.div_ceil(across);
+        let cells = across * down;
+
+        let seed = self.next();
+        let stratum = permute(self.index % cells, cells, seed);
+        let jitter = (to_float(mix(seed, self.index)), to_float(mix(seed ^ 0xa511e9b3, self.index)));
+        (
+            ((stratum % across) as f32 + jitter.0) / across as f32,
+            ((stratum / across) as f32 + jitter.1) / down as f32,
+        )
+    }
+
+    fn rng(&self) -> Pcg {
+        sample_rng(self.seed, self.index)
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+fn radical_inverse(base: u32, mut index: u32) -> f32 {
+    //// Mirror the digits of index (in some base) about the decimal point
+    let mut result = 0.;
+    let mut scale = 1. / base as f64;
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale /= base as f64;
+    }
+    (result as f32).min(1. - f32::EPSILON / 2.)
+}
+
+/////////////////////////////////////////////////////////////////////
+//// The Halton sequence; each dimension uses the radical inverse in
+//// the next prime base. It's shifted by a random amount for each
+//// pixel and dimension, so neighbouring pixels don't look the same.
+//// Dimensions past the primes there are fall back to random numbers
+/////////////////////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct Halton {
+    global: u32,
+    seed: u32,
+    index: u32,
+    dimension: usize,
+}
+
+impl Halton {
+    pub fn new(global: u32) -> Halton {
+        Halton {global, seed: 0, index: 0, dimension: 0}
+    }
+}
+
+impl Sampler for Halton {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.seed = pixel_seed(self.global, pixel);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let shift = to_float(mix(self.seed, self.dimension as u32));
+        let value = match PRIMES.get(self.dimension) {
+            Some(&base) => radical_inverse(base, self.index),
+            None => to_float(mix(self.seed ^ self.index, self.dimension as u32)),
+        };
+        self.dimension += 1;
+
+        // Shift round, wrapping back into [0, 1)
+        let shifted = value + shift;
+        if shifted >= 1. {shifted - 1.} else {shifted}
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+
+    fn rng(&self) -> Pcg {
+        sample_rng(self.seed, self.index)
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+fn sobol(index: u32, dimension: u32) -> u32 {
+    //// One of the first two dimensions of the Sobol sequence, as bits after the point
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+
+    let mut result = 0;
+    let mut direction: u32 = 1 << 31;
+    for bit in 0..32 {
+        if index & (1 << bit) != 0 {
+            result ^= direction;
+        }
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    //// Randomly flip each bit depending on the bits above it, which shuffles the
+    //// points around while keeping them stratified (Burley, "Practical Hash-based
+    //// Owen Scrambling", using the Laine-Karras permutation on reversed bits)
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+/////////////////////////////////////////////////////////////////////////
+//// Owen scrambled Sobol points. Every dimension (or pair) uses the first
+//// one (or two) dimensions of Sobol, which are the best distributed,
+//// with the order of the samples shuffled and the points scrambled
+//// differently for each one so they aren't correlated with each other
+/////////////////////////////////////////////////////////////////////////
+#[derive(Clone)]
+pub struct Sobol {
+    global: u32,
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl Sobol {
+    pub fn new(global: u32) -> Sobol {
+        Sobol {global, seed: 0, index: 0, dimension: 0}
+    }
+
+    fn next(&mut self) -> (u32, u32) {
+        //// A seed for the next dimension, and the shuffled sample to take from it
+        self.dimension += 1;
+        let seed = mix(self.seed, self.dimension);
+        (seed, owen_scramble(self.index, seed))
+    }
+}
+
+impl Sampler for Sobol {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.seed = pixel_seed(self.global, pixel);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let (seed, index) = self.next();
+        to_float(owen_scramble(sobol(index, 0), mix(seed, 0)))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let (seed, index) = self.next();
+        (
+            to_float(owen_scramble(sobol(index, 0), mix(seed, 0))),
+            to_float(owen_scramble(sobol(index, 1), mix(seed, 1))),
+        )
+    }
+
+    fn rng(&self) -> Pcg {
+        sample_rng(self.seed, self.index)
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samplers(seed: u32) -> Vec<Box<dyn Sampler>> {
+        vec![
+            Box::new(Independent::new(seed)),
+            Box::new(Stratified::new(16, seed)),
+            Box::new(Halton::new(seed)),
+            Box::new(Sobol::new(seed)),
+        ]
+    }
+
+    fn numbers(sampler: &mut dyn Sampler, pixel: (u32, u32), index: u32) -> Vec<f32> {
+        //// The first few dimensions of a sample, 1D and 2D, and what its rng starts with
+        sampler.start_pixel_sample(pixel, index);
+        let mut numbers = vec![];
+        for _ in 0..4 {
+            numbers.push(sampler.get_1d());
+            let (a, b) = sampler.get_2d();
+            numbers.extend([a, b]);
+        }
+        numbers.push(sampler.rng().next_float());
+        numbers
+    }
+
+    #[test]
+    fn same_seed_same_numbers() {
+        for (mut a, mut b) in samplers(4839).into_iter().zip(samplers(4839)) {
+            for index in 0..16 {
+                assert_eq!(numbers(&mut *a, (3, 7), index), numbers(&mut *b, (3, 7), index));
+            }
+        }
+    }
+
+    #[test]
+    fn order_doesnt_matter() {
+        //// A sample comes out the same whatever was sampled before it, and in copies
+        for mut sampler in samplers(4839) {
+            let first = numbers(&mut *sampler, (3, 7), 5);
+            numbers(&mut *sampler, (10, 2), 0);
+            numbers(&mut *sampler, (3, 7), 6);
+            assert_eq!(numbers(&mut *sampler, (3, 7), 5), first);
+            assert_eq!(numbers(&mut *sampler.clone_sampler(), (3, 7), 5), first);
+        }
+    }
+
+    #[test]
+    fn seeds_and_pixels_differ() {
+        for (mut a, mut b) in samplers(4839).into_iter().zip(samplers(1)) {
+            assert_ne!(numbers(&mut *a, (3, 7), 0), numbers(&mut *b, (3, 7), 0));
+            assert_ne!(numbers(&mut *a, (3, 7), 0), numbers(&mut *a, (4, 7), 0));
+        }
+    }
+
+    #[test]
+    fn in_range() {
+        for mut sampler in samplers(4839) {
+            for index in 0..64 {
+                assert!(numbers(&mut *sampler, (0, 0), index).iter().all(|x| (0. ..1.).contains(x)));
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_fills_every_stratum() {
+        let mut sampler = Stratified::new(16, 4839);
+        let mut strata = [0; 16];
+        for index in 0..16 {
+            sampler.start_pixel_sample((3, 7), index);
+            strata[(sampler.get_1d() * 16.) as usize] += 1;
+        }
+        assert_eq!(strata, [1; 16]);
+    }
+}
@@ -0,0 +1,45 @@
+use super::ray::*;
+use super::primitive::*;
+use super::medium::*;
+use super::light::*;
+use super::sky::*;
+
+//////////////////////////////////////////////////
+//// Everything there is to render; the objects, whatever
+//// they're sitting in, the lights, and the sky around it all
+//////////////////////////////////////////////////
+pub struct Scene {
+    pub objects: Vec<Box<dyn Object>>,
+    pub volumes: Vec<Volume>,
+    pub lights: Vec<Box<dyn Light>>,
+    pub background: Box<dyn Background>,
+}
+
+impl Scene {
+    pub fn new(objects: Vec<Box<dyn Object>>) -> Scene {
+        //// A scene in a vacuum
+        Scene {
+            objects,
+            volumes: vec![],
+            lights: vec![],
+            background: Box::new(GradientSky::default()),
+        }
+    }
+
+    pub fn emitters(&self) -> Vec<usize> {
+        //// Which objects glow, and can have points picked on them to trace light from
+        self.objects.iter().enumerate()
+            .filter(|(_, object)| object.sample_surface((0.5, 0.5)).is_some_and(|sample| sample.material.emission.is_some()))
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
+
+impl Object for Scene {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        //// The nearest hit, with its material's maps applied
+        let mut hit = self.objects.intersect(ray)?;
+        hit.shading = hit.material.shading_normal(hit.point, hit.normal, hit.uv, hit.dpdu, hit.dpdv);
+        Some(hit)
+    }
+}
@@ -0,0 +1,395 @@
+use super::vector::*;
+use super::colour::*;
+use super::fractal::*;
+
+//////////////////////////////////////////////////////////////////////
+//// A signed distance field, built up as a tree of shapes, ways of
+//// combining them and ways of bending space around them.
+//// Primitives are centred on the origin; move them with Translate
+//////////////////////////////////////////////////////////////////////
+pub enum Sdf {
+    // Primitives
+    Sphere(f32), // Radius
+    Box(Vec3), // Half the size along each axis
+    RoundBox(Vec3, f32), // Half size, corner radius
+    Torus(f32, f32), // Radius of the ring, radius of the tube (lying in xz)
+    Capsule(Vec3, Vec3, f32), // Ends, radius
+    Cylinder(f32, f32), // Radius, half height (along y)
+    Plane(Vec3, f32), // Normal, offset
+    Fractal(Fractal),
+
+    // Combining
+    Union(Box<Sdf>, Box<Sdf>),
+    Subtraction(Box<Sdf>, Box<Sdf>), // The first with the second taken out
+    Intersection(Box<Sdf>, Box<Sdf>),
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f32), // Blending distance
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, f32),
+    SmoothIntersection(Box<Sdf>, Box<Sdf>, f32),
+    Round(Box<Sdf>, f32), // Inflate by a radius
+    Onion(Box<Sdf>, f32), // Hollow shell of a thickness
+
+    // Changing space
+    Translate(Vec3, Box<Sdf>),
+    Rotate(Vec3, f32, Box<Sdf>), // Axis, angle (radians)
+    Scale(f32, Box<Sdf>),
+    Repeat(Vec3, Box<Sdf>), // Period along each axis (0 for no repetition)
+    Mirror(Vec3, Box<Sdf>), // Normal of the mirror plane through the origin
+    Twist(f32, Box<Sdf>), // Radians per unit up the y axis
+    Bend(f32, Box<Sdf>), // Radians per unit along the x axis
+}
+
+fn mix(a: f32, b: f32, h: f32) -> f32 {
+    a * (1. - h) + b * h
+}
+
+impl Sdf {
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match self {
+            Sdf::Sphere(r) => p.length() - r,
+            Sdf::Box(b) => {
+                let q = p.abs() - *b;
+                q.max(0.).length() + q.max_element().min(0.)
+            },
+            Sdf::RoundBox(b, r) => {
+                let q = p.abs() - *b + Vec3::new(*r, *r, *r);
+                q.max(0.).length() + q.max_element().min(0.) - r
+            },
+            Sdf::Torus(big, small) => {
+                let ring = (p.x()*p.x() + p.z()*p.z()).sqrt() - big;
+                (ring*ring + p.y()*p.y()).sqrt() - small
+            },
+            Sdf::Capsule(a, b, r) => {
+                let pa = p - *a;
+                let ba = *b - *a;
+                let h = clamp(dot(pa, ba)/dot(ba, ba));
+                (pa - ba * h).length() - r
+            },
+            Sdf::Cylinder(r, h) => {
+                let dx = (p.x()*p.x() + p.z()*p.z()).sqrt() - r;
+                let dy = p.y().abs() - h;
+                dx.max(dy).min(0.) + (dx.max(0.).powi(2) + dy.max(0.).powi(2)).sqrt()
+            },
+            Sdf::Plane(n, h) => dot(p, *n) + h,
+            Sdf::Fractal(f) => f.distance(p),
+
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::Subtraction(a, b) => a.distance(p).max(-b.distance(p)),
+            Sdf::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Sdf::SmoothUnion(a, b, k) => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = clamp(0.5 + 0.5*(b - a)/k);
+                mix(b, a, h) - k*h*(1. - h)
+            },
+            Sdf::SmoothSubtraction(a, b, k) => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = clamp(0.5 - 0.5*(a + b)/k);
+                mix(a, -b, h) + k*h*(1. - h)
+            },
+            Sdf::SmoothIntersection(a, b, k) => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = clamp(0.5 - 0.5*(b - a)/k);
+                mix(b, a, h) + k*h*(1. - h)
+            },
+            Sdf::Round(a, r) => a.distance(p) - r,
+            Sdf::Onion(a, t) => a.distance(p).abs() - t,
+
+            Sdf::Scale(s, a) => a.distance(self.warp(p)) * s,
+            Sdf::Translate(_, a) | Sdf::Rotate(_, _, a) | Sdf::Repeat(_, a) |
+            Sdf::Mirror(_, a) | Sdf::Twist(_, a) | Sdf::Bend(_, a) => a.distance(self.warp(p)),
+        }
+    }
+
+    fn warp(&self, p: Vec3) -> Vec3 {
+        //// Where a point ends up in the space of the child of a space changing node
+        match self {
+            Sdf::Translate(offset, _) => p - *offset,
+            Sdf::Rotate(axis, angle, _) => rotate(p, *axis, -angle),
+            Sdf::Scale(s, _) => p / *s,
+            Sdf::Repeat(period, _) => {
+                let mut q = p;
+                for i in 0..3 {
+                    if period.value[i] != 0. {
+                        q.value[i] -= period.value[i] * (p.value[i] / period.value[i]).round();
+                    }
+                }
+                q
+            },
+            Sdf::Mirror(n, _) => p - 2. * dot(p, *n).min(0.) * *n,
+            Sdf::Twist(k, _) => {
+                let (s, c) = (k * p.y()).sin_cos();
+                Vec3::new(c*p.x() - s*p.z(), p.y(), s*p.x() + c*p.z())
+            },
+            Sdf::Bend(k, _) => {
+                let (s, c) = (k * p.x()).sin_cos();
+                Vec3::new(c*p.x() - s*p.y(), s*p.x() + c*p.y(), p.z())
+            },
+            _ => p,
+        }
+    }
+
+    pub fn colour(&self, p: Vec3) -> Option<Colour> {
+        //// The colour the field itself gives a point on its surface, if it has one
+        //// (only fractals do, from their orbit traps)
+        match self {
+            Sdf::Fractal(f) => Some(f.colour(p)),
+
+            // Whichever of the two the point is nearer to
+            Sdf::Union(a, b) | Sdf::SmoothUnion(a, b, _) => {
+                if a.distance(p) < b.distance(p) {a.colour(p)} else {b.colour(p)}
+            },
+            Sdf::Subtraction(a, _) | Sdf::Intersection(a, _) |
+            Sdf::SmoothSubtraction(a, _, _) | Sdf::SmoothIntersection(a, _, _) |
+            Sdf::Round(a, _) | Sdf::Onion(a, _) => a.colour(p),
+
+            Sdf::Translate(_, a) | Sdf::Rotate(_, _, a) | Sdf::Scale(_, a) | Sdf::Repeat(_, a) |
+            Sdf::Mirror(_, a) | Sdf::Twist(_, a) | Sdf::Bend(_, a) => a.colour(self.warp(p)),
+
+            _ => None,
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<Sdf, String> {
+        //// Read a distance field from a file; see parse for the format
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Sdf::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Sdf, String> {
+        //// Parse a distance field written as nested brackets, e.g.
+        ////     (smooth-union 0.2
+        ////         (sphere 0.7)
+        ////         (translate 0 1 0 (box 0.5 0.5 0.5)))
+        //// Vectors are written as three numbers, angles in degrees,
+        //// and anything after a ; on a line is a comment
+        let mut parser = Parser {tokens: tokenise(text), position: 0};
+        let sdf = parser.node()?;
+
+        match parser.tokens.get(parser.position) {
+            None => Ok(sdf),
+            Some(token) => Err(format!("Unexpected '{}' after the end of the shape", token)),
+        }
+    }
+}
+
+fn tokenise(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split(';').next().unwrap_or(""))
+        .flat_map(|line| line.replace('(', " ( ").replace(')', " ) ")
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>())
+        .collect()
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<&str, String> {
+        let token = self.tokens.get(self.position).ok_or("Unexpected end of file")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(format!("Expected '{}', found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn number(&mut self) -> Result<f32, String> {
+        let token = self.next()?;
+        token.parse().map_err(|_| format!("Expected a number, found '{}'", token))
+    }
+
+    fn vector(&mut self) -> Result<Vec3, String> {
+        Ok(Vec3::new(self.number()?, self.number()?, self.number()?))
+    }
+
+    fn child(&mut self) -> Result<Box<Sdf>, String> {
+        Ok(Box::new(self.node()?))
+    }
+
+    fn children<F: Fn(Box<Sdf>, Box<Sdf>) -> Sdf>(&mut self, combine: F) -> Result<Sdf, String> {
+        //// Any number (at least one) of shapes folded together, up to the closing bracket
+        let mut sdf = self.node()?;
+        while self.tokens.get(self.position).map(String::as_str) == Some("(") {
+            sdf = combine(Box::new(sdf), self.child()?);
+        }
+        Ok(sdf)
+    }
+
+    fn node(&mut self) -> Result<Sdf, String> {
+        self.expect("(")?;
+        let name = self.next()?.to_string();
+
+        let sdf = match name.as_str() {
+            "sphere" => Sdf::Sphere(self.number()?),
+            "box" => Sdf::Box(self.vector()?),
+            "roundbox" => Sdf::RoundBox(self.vector()?, self.number()?),
+            "torus" => Sdf::Torus(self.number()?, self.number()?),
+            "capsule" => Sdf::Capsule(self.vector()?, self.vector()?, self.number()?),
+            "cylinder" => Sdf::Cylinder(self.number()?, self.number()?),
+            "plane" => Sdf::Plane(self.vector()?.normalise(), self.number()?),
+
+            "union" => self.children(Sdf::Union)?,
+            "subtract" => self.children(Sdf::Subtraction)?,
+            "intersect" => self.children(Sdf::Intersection)?,
+            "smooth-union" => {
+                let k = self.number()?;
+                self.children(|a, b| Sdf::SmoothUnion(a, b, k))?
+            },
+            "smooth-subtract" => {
+                let k = self.number()?;
+                self.children(|a, b| Sdf::SmoothSubtraction(a, b, k))?
+            },
+            "smooth-intersect" => {
+                let k = self.number()?;
+                self.children(|a, b| Sdf::SmoothIntersection(a, b, k))?
+            },
+            "round" => {
+                let r = self.number()?;
+                Sdf::Round(self.child()?, r)
+            },
+            "onion" => {
+                let t = self.number()?;
+                Sdf::Onion(self.child()?, t)
+            },
+
+            "translate" => Sdf::Translate(self.vector()?, self.child()?),
+            "rotate" => Sdf::Rotate(self.vector()?.normalise(), self.number()?.to_radians(), self.child()?),
+            "scale" => Sdf::Scale(self.number()?, self.child()?),
+            "repeat" => Sdf::Repeat(self.vector()?, self.child()?),
+            "mirror" => Sdf::Mirror(self.vector()?.normalise(), self.child()?),
+            "twist" => Sdf::Twist(self.number()?, self.child()?),
+            "bend" => Sdf::Bend(self.number()?, self.child()?),
+
+            "mandelbulb" => self.fractal(Estimator::Mandelbulb {power: 8., bailout: 2.})?,
+            "mandelbox" => self.fractal(Estimator::Mandelbox {
+                scale: -1.5, min_radius: 0.5, fixed_radius: 1., folding_limit: 1.,
+            })?,
+            "menger" => self.fractal(Estimator::Menger)?,
+            "sierpinski" => self.fractal(Estimator::Sierpinski {scale: 2.})?,
+            "julia" => self.fractal(Estimator::Julia {c: [-0.2, 0.6, 0.2, 0.2], bailout: 4.})?,
+            "kifs" => self.fractal(Estimator::Kifs {
+                scale: 2., offset: Vec3::new(1., 1., 1.), axis: Vec3::new(0., 0., 1.), angle: 0.,
+            })?,
+
+            _ => return Err(format!("Unknown shape '{}'", name)),
+        };
+
+        self.expect(")")?;
+        Ok(sdf)
+    }
+
+    fn fractal(&mut self, estimator: Estimator) -> Result<Sdf, String> {
+        //// A fractal's settings are optional, given by name, e.g.
+        ////     (mandelbulb power 4 iterations 12 near 1 0 0)
+        let mut fractal = Fractal::new(estimator);
+
+        while self.tokens.get(self.position).map(String::as_str) != Some(")") {
+            let key = self.next()?.to_string();
+
+            match (key.as_str(), &mut fractal.estimator) {
+                ("iterations", _) => fractal.iterations = self.number()? as u32,
+                ("near", _) => fractal.near = self.vector()?,
+                ("far", _) => fractal.far = self.vector()?,
+
+                ("power", Estimator::Mandelbulb {power, ..}) => *power = self.number()?,
+                ("bailout", Estimator::Mandelbulb {bailout, ..}) |
+                ("bailout", Estimator::Julia {bailout, ..}) => *bailout = self.number()?,
+                ("scale", Estimator::Mandelbox {scale, ..}) |
+                ("scale", Estimator::Sierpinski {scale}) |
+                ("scale", Estimator::Kifs {scale, ..}) => *scale = self.number()?,
+                ("min-radius", Estimator::Mandelbox {min_radius, ..}) => *min_radius = self.number()?,
+                ("fixed-radius", Estimator::Mandelbox {fixed_radius, ..}) => *fixed_radius = self.number()?,
+                ("folding-limit", Estimator::Mandelbox {folding_limit, ..}) => *folding_limit = self.number()?,
+                ("c", Estimator::Julia {c, ..}) => {
+                    *c = [self.number()?, self.number()?, self.number()?, self.number()?];
+                },
+                ("offset", Estimator::Kifs {offset, ..}) => *offset = self.vector()?,
+                ("axis", Estimator::Kifs {axis, ..}) => *axis = self.vector()?.normalise(),
+                ("angle", Estimator::Kifs {angle, ..}) => *angle = self.number()?.to_radians(),
+
+                _ => return Err(format!("Unknown fractal setting '{}'", key)),
+            }
+        }
+
+        Ok(Sdf::Fractal(fractal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Sdf {
+        match Sdf::parse(text) {
+            Ok(sdf) => sdf,
+            Err(e) => panic!("{}: {}", text, e),
+        }
+    }
+
+    fn error(text: &str) -> String {
+        Sdf::parse(text).err().expect("should not have parsed")
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn sphere() {
+        let sdf = parse("(sphere 0.5)");
+        assert!(close(sdf.distance(Vec3::new(2., 0., 0.)), 1.5));
+        assert!(close(sdf.distance(Vec3::new(0., 0., 0.)), -0.5));
+    }
+
+    #[test]
+    fn comments_and_nesting() {
+        let sdf = parse("
+            ; Two balls side by side
+            (union
+                (translate -1 0 0 (sphere 0.5)) ; the left one
+                (translate 1 0 0 (sphere 0.5)))");
+        assert!(close(sdf.distance(Vec3::new(-1., 0., 0.)), -0.5));
+        assert!(close(sdf.distance(Vec3::new(1., 0., 0.)), -0.5));
+        assert!(close(sdf.distance(Vec3::new(0., 0., 0.)), 0.5));
+    }
+
+    #[test]
+    fn any_number_of_children() {
+        let sdf = parse("(union (sphere 1) (translate 3 0 0 (sphere 1)) (translate 6 0 0 (sphere 1)))");
+        assert!(close(sdf.distance(Vec3::new(6., 0., 0.)), -1.));
+    }
+
+    #[test]
+    fn fractal_settings() {
+        match parse("(mandelbulb power 4 iterations 12)") {
+            Sdf::Fractal(Fractal {iterations: 12, estimator: Estimator::Mandelbulb {power, ..}, ..}) => assert_eq!(power, 4.),
+            _ => panic!("Not the mandelbulb asked for"),
+        }
+    }
+
+    #[test]
+    fn scenes() {
+        parse(include_str!("../scenes/blob.sdf"));
+        parse(include_str!("../scenes/mandelbox.sdf"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("(cube 1)"), "Unknown shape 'cube'");
+        assert_eq!(error("(sphere big)"), "Expected a number, found 'big'");
+        assert_eq!(error("(sphere 1"), "Unexpected end of file");
+        assert_eq!(error("sphere 1"), "Expected '(', found 'sphere'");
+        assert_eq!(error("(sphere 1 2)"), "Expected ')', found '2'");
+        assert_eq!(error("(sphere 1) (sphere 2)"), "Unexpected '(' after the end of the shape");
+        assert_eq!(error("(menger power 3)"), "Unknown fractal setting 'power'");
+        assert_eq!(error(""), "Unexpected end of file");
+    }
+}
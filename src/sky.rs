@@ -0,0 +1,172 @@
+use super::vector::*;
+use super::colour::*;
+use super::material::blackbody;
+use super::light::*;
+
+///////////////////////////////////////////////////////
+//// What rays see when they don't hit anything at all
+///////////////////////////////////////////////////////
+pub trait Background: Send + Sync {
+    fn radiance(&self, d: Vec3) -> Colour;
+}
+
+/////////////////////////////////////////////////////////////////
+//// The original sky; blue fading to white going up, with a sun
+/////////////////////////////////////////////////////////////////
+pub struct GradientSky {
+    pub sun: Vec3, // Direction towards the sun
+}
+
+impl Default for GradientSky {
+    fn default() -> GradientSky {
+        GradientSky {sun: Vec3::new(-1., 1., -1.)}
+    }
+}
+
+impl Background for GradientSky {
+    fn radiance(&self, d: Vec3) -> Colour {
+        //// A function which gives a sky colour based on a direction
+
+        // Sun colour
+        let sunlight = Colour::white() * clamp(dot(self.sun.normalise(), d) + 0.03).powf(300.);
+
+        // Lerp between blue and white vertically
+        let val = ((1. + d.y())/2.).powf(1.5);
+        let sky = (Colour::new(0.45, 0.68, 0.87) * (1.-val) + Colour::white() *  val) * 0.4;
+
+        return sunlight * 1. + sky;
+    }
+}
+
+// Perez function coefficients (A to E) for luminance and the two chromaticities,
+// each as a linear function of turbidity (Preetham, Shirley and Smits)
+const PEREZ_Y: [(f32, f32); 5] = [(0.1787, -1.4630), (-0.3554, 0.4275), (-0.0227, 5.3251), (0.1206, -2.5771), (-0.0670, 0.3703)];
+const PEREZ_X: [(f32, f32); 5] = [(-0.0193, -0.2592), (-0.0665, 0.0008), (-0.0004, 0.2125), (-0.0641, -0.8989), (-0.0033, 0.0452)];
+const PEREZ_YC: [(f32, f32); 5] = [(-0.0167, -0.2608), (-0.0950, 0.0092), (-0.0079, 0.2102), (-0.0441, -1.6537), (-0.0109, 0.0529)];
+
+fn perez(coefficients: [f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    //// Relative brightness at some angle from the zenith (theta) and from the sun (gamma)
+    let [a, b, c, d, e] = coefficients;
+    (1. + a * (b / cos_theta.max(0.01)).exp()) * (1. + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Colour {
+    //// CIE xyY to linear sRGB
+    let (big_x, big_z) = (x / y * luminance, (1. - x - y) / y * luminance);
+    Colour::new(
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    ).max(0.)
+}
+
+//////////////////////////////////////////////////////////////////////////
+//// A clear daylight sky (Preetham's analytic model). The sun is left out
+//// of the sky itself, since it's far too small and bright to find by
+//// chance; `sun` gives a light for it instead. Below the horizon is
+//// ground, lit by the sky and sun. Angles are in radians; azimuth goes
+//// round from +z towards +x. Turbidity is how hazy it is (2 is very
+//// clear, 10 is hazy). The sky's values are in kcd/m², so `brightness`
+//// scales them down to the scene's
+//////////////////////////////////////////////////////////////////////////
+pub struct PhysicalSky {
+    pub sun_direction: Vec3,
+    pub turbidity: f32,
+    pub brightness: f32,
+    zenith: (f32, f32, f32), // Luminance and chromaticity straight up
+    coefficients: [[f32; 5]; 3],
+    ground: Colour,
+}
+
+impl PhysicalSky {
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, ground_albedo: Colour, brightness: f32) -> PhysicalSky {
+        let sun_direction = Vec3::new(elevation.cos() * azimuth.sin(), elevation.sin(), elevation.cos() * azimuth.cos());
+        let t = turbidity;
+        let theta = PI / 2. - elevation.max(0.); // Sun's angle from the zenith
+        let (theta2, theta3) = (theta * theta, theta * theta * theta);
+
+        let chi = (4. / 9. - t / 120.) * (PI - 2. * theta);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.);
+        let zenith_x = t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+            + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+        let zenith_y = t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+            + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+
+        let linear = |table: [(f32, f32); 5]| table.map(|(slope, offset)| slope * t + offset);
+
+        let mut sky = PhysicalSky {
+            sun_direction,
+            turbidity,
+            brightness,
+            zenith: (zenith_luminance, zenith_x, zenith_y),
+            coefficients: [linear(PEREZ_Y), linear(PEREZ_X), linear(PEREZ_YC)],
+            ground: Colour::black(),
+        };
+
+        // The ground is diffuse, so it's lit by everything above it, sun included
+        let mut irradiance = sky.sun().sample(Vec3::new(0., 0., 0.), (0.5, 0.5))
+            .map_or(Colour::black(), |sun| sun.radiance * clamp(sun.direction.y()));
+        const STEPS: u32 = 32;
+        for i in 0..STEPS {
+            for j in 0..STEPS {
+                // Evenly over the hemisphere, weighted by cos
+                let (a, b) = ((i as f32 + 0.5) / STEPS as f32, (j as f32 + 0.5) / STEPS as f32);
+                let (r, phi) = (a.sqrt(), 2. * PI * b);
+                let d = Vec3::new(r * phi.cos(), (1. - a).sqrt(), r * phi.sin());
+                irradiance += sky.sky(d) * (PI / (STEPS * STEPS) as f32);
+            }
+        }
+        sky.ground = ground_albedo * irradiance * (1. / PI);
+
+        sky
+    }
+
+    fn sky(&self, d: Vec3) -> Colour {
+        //// The sky above the horizon
+        let d = Vec3::new(d.x(), d.y().max(0.001), d.z()).normalise();
+        let cos_theta = d.y();
+        let gamma = dot(d, self.sun_direction).clamp(-1., 1.).acos();
+        let theta_sun = self.sun_direction.y().clamp(0., 1.).acos();
+
+        let relative = |i: usize| perez(self.coefficients[i], cos_theta, gamma) / perez(self.coefficients[i], 1., theta_sun);
+        let (luminance, x, y) = self.zenith;
+
+        xyy_to_rgb(x * relative(1), y * relative(2), luminance * relative(0)) * self.brightness
+    }
+
+    pub fn sun(&self) -> DirectionalLight {
+        //// The sun, dimmed and reddened by the air it comes through
+        let elevation = self.sun_direction.y().clamp(0., 1.).asin();
+        let zenith_degrees = 90. - elevation.to_degrees();
+
+        // How much air it's going through, compared to straight up (Kasten and Young)
+        let mass = 1. / (elevation.sin() + 0.15 * (93.885 - zenith_degrees).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+
+        // Rayleigh and aerosol scattering at a red, green and blue wavelength (in µm)
+        let transmittance = |wavelength: f32| {
+            (-0.008735 * wavelength.powf(-4.08) * mass).exp() * (-beta * wavelength.powf(-1.3) * mass).exp()
+        };
+        let colour = blackbody(5778.) * Colour::new(transmittance(0.65), transmittance(0.57), transmittance(0.475));
+
+        DirectionalLight {
+            direction: -self.sun_direction,
+            colour,
+            // Sunlight is about a hundred thousand lux, which is a hundred kcd/m² sr
+            irradiance: if elevation > 0. {100. * self.brightness} else {0.},
+            angular_diameter: 0.0093,
+        }
+    }
+}
+
+impl Background for PhysicalSky {
+    fn radiance(&self, d: Vec3) -> Colour {
+        let d = d.normalise();
+        if d.y() < 0. {
+            return self.ground;
+        }
+        self.sky(d)
+    }
+}
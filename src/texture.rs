@@ -1,55 +1,235 @@
 use super::vector::*;
 use super::colour::*;
-use image::{GenericImageView};
+use super::noise::*;
+use image::RgbImage;
 
 //const PI: f32 = 3.14159;
 
-pub fn to_angles(v: Vec3) -> (f32, f32) {
-    ((v.z()/v.length()).acos(), v.x().atan2(v.y()))
+pub trait Texture: Send + Sync {
+    // uv are the surface coordinates (mostly from 0 to 1 across a shape),
+    // p is the point in space; textures can use whichever suits them
+    fn value(&self, uv: (f32, f32), p: Vec3) -> Colour;
 }
 
-pub trait Texture {
-    fn value(&self, u: f32, v: f32) -> Colour;
-    // I guess maybe u, v are from -1 to 1 or something
-}
-
-pub struct TextConstant {
-    col: Colour,
-}
-
-impl Texture for TextConstant {
-    fn value(&self, _: f32, _: f32) -> Colour {
-        self.col
+impl Texture for Colour {
+    //// A plain colour is a texture that's the same everywhere
+    fn value(&self, _: (f32, f32), _: Vec3) -> Colour {
+        *self
     }
 }
 
 pub struct TextCheck {
-    col1: Colour,
-    col2: Colour,
+    pub col1: Colour,
+    pub col2: Colour,
 }
 
 impl Texture for TextCheck {
-    fn value(&self, u: f32, v: f32) -> Colour{
+    fn value(&self, (u, v): (f32, f32), _: Vec3) -> Colour{
         let lerp = ((3. * u) % 1.).abs().round() * ((3. * v) % 1.).abs().round();
         lerp * self.col1 + (1.-lerp) * self.col2
     }
 }
 
-pub fn text_check(v: Vec3) -> Colour {
-    let (t, p) = to_angles(v);
-    Colour::white() * ((3. * t) % 1.).abs().round() * ((3. * p) % 1.).abs().round()
+/////////////////////////////////////////////////////////////////
+//// An image stretched over uv, (0, 0) at the bottom left. Colour
+//// images are stored gamma encoded, and need decoding to be used
+//// as colours; normal and bump maps aren't
+/////////////////////////////////////////////////////////////////
+pub struct TextImage {
+    pub image: RgbImage,
+    pub gamma: bool,
+}
+
+impl TextImage {
+    pub fn load(filename: &str, gamma: bool) -> Result<TextImage, String> {
+        let image = image::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Ok(TextImage {image: image.to_rgb(), gamma})
+    }
+}
+
+impl Texture for TextImage {
+    fn value(&self, (u, v): (f32, f32), _: Vec3) -> Colour {
+        // Wrap around, then pick the nearest pixel
+        let (width, height) = self.image.dimensions();
+        let x = ((u - u.floor()) * width as f32) as u32;
+        let y = ((1. - (v - v.floor())) * height as f32) as u32;
+
+        let pixel = self.image.get_pixel(x.min(width - 1), y.min(height - 1));
+        let col = Colour::new(pixel[0] as f32 / 255., pixel[1] as f32 / 255., pixel[2] as f32 / 255.);
+
+        if self.gamma {
+            col.gamma_decode()
+        } else {
+            col
+        }
+    }
+}
+
+///////////////////////////////////////////////////////
+//// Which sort of gradient noise a texture is made of
+///////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub enum Noise {
+    Perlin,
+    Simplex,
+}
+
+impl Noise {
+    fn function(self) -> fn(Vec3) -> f32 {
+        match self {
+            Noise::Perlin => perlin,
+            Noise::Simplex => simplex,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////
+//// Where a procedural texture is worked out; at the point in space,
+//// or laid out over the surface's uv (at (u, v, 0)), which stays put
+//// on the surface when it moves or is instanced somewhere else
+///////////////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub enum Space {
+    Point,
+    Uv,
+}
+
+impl Space {
+    fn at(self, (u, v): (f32, f32), p: Vec3) -> Vec3 {
+        match self {
+            Space::Point => p,
+            Space::Uv => Vec3::new(u, v, 0.),
+        }
+    }
+}
+
+fn mix(a: Colour, b: Colour, t: f32) -> Colour {
+    a * (1. - t) + b * t
 }
 
-fn text_image(v: Vec3, img: image::DynamicImage) -> Colour {
+///////////////////////////////////////////////////////////////
+//// Fractal noise through space, blending between two colours
+///////////////////////////////////////////////////////////////
+pub struct TextNoise {
+    pub noise: Noise,
+    pub space: Space,
+    pub scale: f32, // Size of the biggest lumps
+    pub octaves: u32,
+    pub turbulent: bool, // Turbulence rather than plain fbm
+    pub col1: Colour,
+    pub col2: Colour,
+}
+
+impl Texture for TextNoise {
+    fn value(&self, uv: (f32, f32), p: Vec3) -> Colour {
+        let p = self.space.at(uv, p) / self.scale;
+        let t = if self.turbulent {
+            turbulence(self.noise.function(), p, self.octaves)
+        } else {
+            0.5 + 0.5 * fbm(self.noise.function(), p, self.octaves)
+        };
+        mix(self.col1, self.col2, clamp(t))
+    }
+}
+
+/////////////////////////////////////////////////////////////////////
+//// Marble; bands along x, pushed around by turbulence into veins
+/////////////////////////////////////////////////////////////////////
+pub struct TextMarble {
+    pub noise: Noise,
+    pub space: Space,
+    pub scale: f32, // Distance between veins
+    pub turbulence: f32, // How wavy they are
+    pub octaves: u32,
+    pub base: Colour,
+    pub vein: Colour,
+}
+
+impl Texture for TextMarble {
+    fn value(&self, uv: (f32, f32), p: Vec3) -> Colour {
+        let p = self.space.at(uv, p) / self.scale;
+        let wave = (p.x() * PI + self.turbulence * turbulence(self.noise.function(), p, self.octaves) * 2. * PI).sin();
+        // Veins where the wave is near 0 rather than spread evenly
+        mix(self.vein, self.base, wave.abs().powf(0.3))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+//// Wood; rings around the y axis (the trunk), wobbled a bit by noise
+////////////////////////////////////////////////////////////////////////
+pub struct TextWood {
+    pub noise: Noise,
+    pub space: Space,
+    pub scale: f32, // Distance between rings
+    pub wobble: f32,
+    pub light: Colour,
+    pub dark: Colour,
+}
+
+impl Texture for TextWood {
+    fn value(&self, uv: (f32, f32), p: Vec3) -> Colour {
+        let p = self.space.at(uv, p) / self.scale;
+        let radius = (p.x()*p.x() + p.z()*p.z()).sqrt() + self.wobble * fbm(self.noise.function(), p, 3);
+        let ring = radius - radius.floor();
+        // Sharp dark edge to each ring, fading back to light
+        mix(self.dark, self.light, ring.powf(0.5))
+    }
+}
+
+///////////////////////////////////////////////////////////////
+//// Granite; speckles of a few colours from high frequency
+//// turbulence, with crystals picked out where it peaks
+///////////////////////////////////////////////////////////////
+pub struct TextGranite {
+    pub noise: Noise,
+    pub space: Space,
+    pub scale: f32, // Grain size
+    pub base: Colour,
+    pub grain: Colour,
+    pub crystal: Colour,
+}
+
+impl Texture for TextGranite {
+    fn value(&self, uv: (f32, f32), p: Vec3) -> Colour {
+        let p = self.space.at(uv, p) / self.scale;
+        let t = turbulence(self.noise.function(), p, 4);
+        let grain = mix(self.base, self.grain, clamp(t * 2.5));
+        // A few spots much brighter than the rest
+        let crystal = clamp((self.noise.function()(p * 3.7) - 0.45) * 8.);
+        mix(grain, self.crystal, crystal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn textures(space: Space) -> Vec<Box<dyn Texture>> {
+        let (a, b) = (Colour::new(1., 0., 0.), Colour::new(0., 0., 1.));
+        vec![
+            Box::new(TextNoise {noise: Noise::Perlin, space, scale: 0.3, octaves: 4, turbulent: false, col1: a, col2: b}),
+            Box::new(TextNoise {noise: Noise::Simplex, space, scale: 0.3, octaves: 4, turbulent: true, col1: a, col2: b}),
+            Box::new(TextMarble {noise: Noise::Perlin, space, scale: 0.2, turbulence: 2., octaves: 4, base: a, vein: b}),
+            Box::new(TextWood {noise: Noise::Simplex, space, scale: 0.1, wobble: 0.3, light: a, dark: b}),
+            Box::new(TextGranite {noise: Noise::Perlin, space, scale: 0.05, base: a, grain: b, crystal: Colour::white()}),
+        ]
+    }
 
-    //let mut img = image::open("worldmap.png").unwrap();
+    #[test]
+    fn uv_space_sticks_to_the_surface() {
+        //// The same point on a surface, wherever the surface has moved to
+        let uv = (0.37, 0.81);
+        let (here, there) = (Vec3::new(0.3, 1.2, -0.4), Vec3::new(5.1, -2.7, 3.3));
+        for texture in textures(Space::Uv) {
+            assert_eq!(texture.value(uv, here).value, texture.value(uv, there).value);
+        }
+    }
 
-    let (t, p) = to_angles(v);
-    let x = (t/PI*img.width() as f32).round() as u32;
-    let y = (p/(2.*PI)*img.height() as f32).round() as u32;
-    
-    
-    let col = img.get_pixel(x, y);
-    //Colour {r: col[0] as f32 / 255., g: col[1] as f32 / 255., b: col[2] as f32 / 255.}
-    Colour::new(col[0] as f32 / 255., col[1] as f32 / 255., col[2] as f32 / 255.)
+    #[test]
+    fn point_space_ignores_uv() {
+        let p = Vec3::new(0.3, 1.2, -0.4);
+        for texture in textures(Space::Point) {
+            assert_eq!(texture.value((0.1, 0.2), p).value, texture.value((0.9, 0.6), p).value);
+        }
+    }
 }
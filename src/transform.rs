@@ -0,0 +1,92 @@
+use super::vector::*;
+use super::ray::*;
+use super::primitive::*;
+
+/////////////////////////////////////////////////////////////
+//// Moves something from its own space into the world:
+//// scale (uniformly), then rotate about an axis, then move
+/////////////////////////////////////////////////////////////
+#[derive(Copy, Clone)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub axis: Vec3, // Normalised
+    pub angle: f32, // Radians
+    pub scale: f32,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            translation: Vec3::new(0., 0., 0.),
+            axis: Vec3::new(0., 1., 0.),
+            angle: 0.,
+            scale: 1.,
+        }
+    }
+
+    pub fn lerp(&self, other: &Transform, along: f32) -> Transform {
+        //// Partway between two transforms; rotations blend the axis and angle,
+        //// which is exact when they share an axis (the usual case for spinning things)
+        let mix = |a: f32, b: f32| a * (1. - along) + b * along;
+        let axis = self.axis * (1. - along) + other.axis * along;
+
+        Transform {
+            translation: self.translation * (1. - along) + other.translation * along,
+            axis: if axis.length_squared() > 0. {axis.normalise()} else {self.axis},
+            angle: mix(self.angle, other.angle),
+            scale: mix(self.scale, other.scale),
+        }
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        rotate(p * self.scale, self.axis, self.angle) + self.translation
+    }
+
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        rotate(v * self.scale, self.axis, self.angle)
+    }
+
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        // Scaling is uniform, so normals only need rotating
+        rotate(n, self.axis, self.angle)
+    }
+
+    pub fn inverse_point(&self, p: Vec3) -> Vec3 {
+        rotate(p - self.translation, self.axis, -self.angle) / self.scale
+    }
+
+    pub fn inverse_vector(&self, v: Vec3) -> Vec3 {
+        rotate(v, self.axis, -self.angle) / self.scale
+    }
+}
+
+////////////////////////////////////////////////////////////////////
+//// An object placed in the world by a transform which can change
+//// over time; start at time0 and end at time1, blending in between
+////////////////////////////////////////////////////////////////////
+pub struct Instance {
+    pub object: Box<dyn Object>,
+    pub start: Transform,
+    pub end: Transform,
+    pub time0: f32,
+    pub time1: f32,
+}
+
+impl Object for Instance {
+    fn intersect(&self, ray: Ray) -> Option<SurfaceInteraction<'_>> {
+        let along = ((ray.time - self.time0) / (self.time1 - self.time0)).clamp(0., 1.);
+        let transform = self.start.lerp(&self.end, along);
+
+        // Distances along the ray are the same in both spaces,
+        // since the direction gets scaled along with everything else
+        let local = Ray::new(transform.inverse_point(ray.o), transform.inverse_vector(ray.d), ray.time);
+
+        let mut hit = self.object.intersect(local)?;
+        hit.point = transform.point(hit.point);
+        hit.normal = transform.normal(hit.normal);
+        hit.shading = transform.normal(hit.shading);
+        hit.dpdu = transform.vector(hit.dpdu);
+        hit.dpdv = transform.vector(hit.dpdv);
+        Some(hit)
+    }
+}
@@ -2,7 +2,7 @@ use std::ops::{Add, Sub, Mul, Div, AddAssign, Neg};
 //use std::ops::*;
 //? use std::f32;
 
-pub const PI: f32 = 3.14159;
+pub const PI: f32 = std::f32::consts::PI;
 
 // Vector construct
 #[derive(Copy, Clone)]
@@ -27,6 +27,30 @@ pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {
 
 }
 
+pub fn basis(n: Vec3) -> (Vec3, Vec3) {
+    // Two vectors making an orthonormal basis with the (normalised) vector n
+    let tangent = if n.x().abs() > n.y().abs() {
+        Vec3::new(n.z(), 0., -n.x()).normalise()
+    } else {
+        Vec3::new(0., -n.z(), n.y()).normalise()
+    };
+    (tangent, cross(n, tangent))
+}
+
+pub fn cosine_sample(n: Vec3, (a, b): (f32, f32)) -> Vec3 {
+    // A direction around the (normalised) vector n, more likely the closer it is to n (by cos)
+    let (tangent, bitangent) = basis(n);
+    let r = a.sqrt();
+    let phi = 2. * PI * b;
+    (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + n * (1. - a).max(0.).sqrt()).normalise()
+}
+
+pub fn rotate(v: Vec3, axis: Vec3, angle: f32) -> Vec3 {
+    // Rotate v anticlockwise about the (normalised) axis by angle radians (Rodrigues)
+    let (sin, cos) = angle.sin_cos();
+    v * cos + cross(axis, v) * sin + axis * dot(axis, v) * (1. - cos)
+}
+
 impl Add for Vec3 {
     type Output = Vec3;
 
@@ -109,7 +133,7 @@ impl Neg for Vec3 {
 
 // Some convenience functions
 impl Vec3 {
-    pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
+    pub const fn new(x: f32, y: f32, z: f32) -> Vec3 {
         Vec3 {value: [x, y, z]}
     }
 
@@ -142,4 +166,17 @@ impl Vec3 {
     pub fn normalise(self) -> Vec3 {
         self / self.length()
     }
+
+    pub fn abs(self) -> Vec3 {
+        Vec3::new(self.value[0].abs(), self.value[1].abs(), self.value[2].abs())
+    }
+
+    pub fn max(self, t: f32) -> Vec3 {
+        // Componentwise maximum with a number
+        Vec3::new(self.value[0].max(t), self.value[1].max(t), self.value[2].max(t))
+    }
+
+    pub fn max_element(&self) -> f32 {
+        self.value[0].max(self.value[1]).max(self.value[2])
+    }
 }
\ No newline at end of file